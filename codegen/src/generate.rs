@@ -0,0 +1,168 @@
+// Rust entities from the columns of tables: a struct per table, named after it
+// in camel case, with a field per column in column order, as the ORM maps
+// columns to fields by position.
+
+use ormlib::ColumnInfo;
+use ormlib::schema::{column_kind, Dialect, Kind};
+
+pub fn header(url: &str) -> String {
+    format!("// Generated by ormlib-codegen from {url}\n\n\
+        use ormlib_derive::{{TableDeserialize, TableSerialize}};\n\
+        use serde_derive::{{Deserialize, Serialize}};\n")
+}
+
+// The struct of a table, or a comment which says why there is none.
+pub fn entity(dialect: Dialect, table: &str, columns: &[ColumnInfo]) -> String {
+    let invalid: Vec<&str> = columns.iter()
+        .map(|column| column.name.as_str())
+        .filter(|name| !is_field_name(name))
+        .collect();
+    if !invalid.is_empty() {
+        return format!("\n// skipped table {:?}: the columns {:?} can't be field names\n", table, invalid);
+    }
+    let mut output = format!("\n#[derive(TableDeserialize, TableSerialize, Serialize, Deserialize, Debug, Clone, PartialEq)]\n\
+        #[table(name = {:?})]\npub struct {} {{\n", table, struct_name(table));
+    for column in columns {
+        let (rust_type, note) = rust_type(dialect, column.declared_type.as_str());
+        if let Some(note) = note {
+            output.push_str(format!("    // {}\n", note).as_str());
+        }
+        let rust_type = match column.nullable && !column.primary_key {
+            true => format!("Option<{}>", rust_type),
+            false => rust_type.to_string(),
+        };
+        // a key which the database doesn't fill in, e.g. a UUID
+        if column.name == "id" && !column.auto_increment {
+            output.push_str("    #[column(generated = false)]\n");
+        }
+        output.push_str(format!("    pub {}: {},\n", column.name, rust_type).as_str());
+    }
+    output.push_str("}\n");
+    output
+}
+
+// `user_log` is `UserLog`.
+fn struct_name(table: &str) -> String {
+    let name: String = table.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            let first = chars.next().map(|c| c.to_ascii_uppercase()).unwrap_or_default();
+            format!("{}{}", first, chars.as_str())
+        })
+        .collect();
+    match name.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => name,
+        _ => format!("Table{}", name),
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "false", "fn",
+    "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self", "Self",
+    "static", "struct", "super", "trait", "true", "type", "unsafe", "use", "where", "while", "abstract", "become",
+    "box", "do", "final", "macro", "override", "priv", "try", "typeof", "unsized", "virtual", "yield",
+];
+
+// Fields are named as their columns, so a column has to be an identifier which
+// isn't a keyword.
+fn is_field_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let start = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    start && name != "_" && chars.all(|c| c.is_ascii_alphanumeric() || c == '_') && !KEYWORDS.contains(&name)
+}
+
+// The type of a field for the declared type of its column, as `schema` reads
+// the column, with a note for the types which are kept as text. Booleans are
+// integers, as they are stored. Dates and decimals are text unless their
+// feature is enabled: `chrono` or `time`, and `rust_decimal`.
+fn rust_type(dialect: Dialect, declared_type: &str) -> (&'static str, Option<String>) {
+    let declared = declared_type.to_lowercase();
+    let text_note = || Some(format!("{} as text", declared_type));
+    let hex_note = || Some(format!("{} as hex", if declared.is_empty() { "no type" } else { declared_type }));
+    let base = declared.split(['(', ' ']).next().unwrap_or_default();
+    match column_kind(dialect, declared_type) {
+        Kind::Integer if dialect == Dialect::Sqlite => ("i64", None),
+        Kind::Integer => match (base, declared.contains("unsigned")) {
+            ("tinyint" | "bool" | "boolean", false) => ("i8", None),
+            ("tinyint", true) => ("u8", None),
+            ("smallint", false) => ("i16", None),
+            ("smallint", true) => ("u16", None),
+            ("mediumint" | "int" | "integer", false) => ("i32", None),
+            ("mediumint" | "int" | "integer", true) => ("u32", None),
+            ("bigint", false) => ("i64", None),
+            ("bigint", true) => ("u64", None),
+            _ => ("String", hex_note()),
+        },
+        Kind::Real => ("f64", None),
+        Kind::Text => ("String", None),
+        Kind::Blob => ("String", hex_note()),
+        Kind::Date if cfg!(feature = "chrono") => ("chrono::NaiveDate", None),
+        Kind::Date if cfg!(feature = "time") => ("time::Date", None),
+        Kind::DateTime if cfg!(feature = "chrono") => ("chrono::NaiveDateTime", None),
+        Kind::DateTime if cfg!(feature = "time") => ("time::PrimitiveDateTime", None),
+        Kind::Decimal if cfg!(feature = "rust_decimal") => ("rust_decimal::Decimal", None),
+        Kind::Other if dialect == Dialect::Sqlite && declared.starts_with("bool") => ("i64", None),
+        Kind::Other if dialect == Dialect::Mysql && matches!(base, "enum" | "set" | "json") => ("String", None),
+        _ => ("String", text_note()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, declared_type: &str, nullable: bool, primary_key: bool) -> ColumnInfo {
+        ColumnInfo {
+            name: name.to_string(),
+            declared_type: declared_type.to_string(),
+            nullable,
+            default: None,
+            primary_key,
+            auto_increment: primary_key,
+        }
+    }
+
+    #[test]
+    fn test_entity() {
+        let columns = vec![
+            column("id", "INTEGER", true, true),
+            column("name", "VARCHAR(50)", true, false),
+            column("score", "DOUBLE", false, false),
+            column("active", "BOOLEAN", false, false),
+            column("born", "TIME", true, false),
+        ];
+        assert_eq!(entity(Dialect::Sqlite, "user_log", &columns), "
+#[derive(TableDeserialize, TableSerialize, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[table(name = \"user_log\")]
+pub struct UserLog {
+    pub id: i64,
+    pub name: Option<String>,
+    pub score: f64,
+    pub active: i64,
+    // TIME as text
+    pub born: Option<String>,
+}
+");
+        let mut key = column("id", "CHAR(36)", false, true);
+        key.auto_increment = false;
+        assert!(entity(Dialect::Mysql, "token", &[key]).contains("    #[column(generated = false)]\n    pub id: String,\n"));
+        let columns = vec![column("id", "int unsigned", false, true), column("type", "text", false, false)];
+        assert!(entity(Dialect::Mysql, "1st", &columns).starts_with("\n// skipped table \"1st\""));
+        assert_eq!("Table1st", struct_name("1st"));
+        assert_eq!(("i8", None), rust_type(Dialect::Mysql, "tinyint(1)"));
+        assert_eq!(("u32", None), rust_type(Dialect::Mysql, "int(10) unsigned"));
+        assert_eq!(("u8", None), rust_type(Dialect::Mysql, "tinyint(4) unsigned"));
+        assert_eq!(("String", Some("bit(8) as hex".to_string())), rust_type(Dialect::Mysql, "bit(8)"));
+        assert_eq!(("f64", None), rust_type(Dialect::Mysql, "float"));
+        #[cfg(feature = "chrono")]
+        assert_eq!(("chrono::NaiveDateTime", None), rust_type(Dialect::Sqlite, "DATETIME"));
+        #[cfg(not(any(feature = "chrono", feature = "time")))]
+        assert_eq!(("String", Some("date as text".to_string())), rust_type(Dialect::Mysql, "date"));
+        #[cfg(feature = "rust_decimal")]
+        assert_eq!(("rust_decimal::Decimal", None), rust_type(Dialect::Mysql, "decimal(10,2)"));
+        #[cfg(not(feature = "rust_decimal"))]
+        assert_eq!(("String", Some("NUMERIC as text".to_string())), rust_type(Dialect::Sqlite, "NUMERIC"));
+        assert_eq!(("String", Some("time as text".to_string())), rust_type(Dialect::Mysql, "time"));
+    }
+}
@@ -0,0 +1,185 @@
+// Typed aggregates over the table of an entity:
+//
+//     let adults: i64 = conn.count::<User>().filter("age >= 18").run().await?;
+//     let any: bool = conn.exists::<User>().filter("name = 'John'").run().await?;
+//     let total: Option<i64> = conn.sum::<User, i64>("age").run().await?;
+//     let per_country: Vec<(String, i64)> = conn.group_by::<User, String>("country").count().run().await?;
+//
+// The table and the columns are those of `TableDeserialize`, and soft-deleted
+// rows are left out as in `find_all`.
+
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use crate::{ORMError, ORMTrait, Row, TableDeserialize};
+
+pub struct Aggregate<'a, R, T, O: ORMTrait<O>> {
+    pub(crate) orm: &'a O,
+    // `count`, `sum`, `avg`, `min`, `max`, or `exists`
+    function: &'static str,
+    // the argument of the function, `*` when there is none
+    column: Option<String>,
+    filter: Option<String>,
+    group_by: Option<String>,
+    result: PhantomData<R>,
+    entity: PhantomData<T>,
+}
+
+pub struct GroupBy<'a, K, T, O: ORMTrait<O>> {
+    orm: &'a O,
+    column: String,
+    filter: Option<String>,
+    key: PhantomData<K>,
+    entity: PhantomData<T>,
+}
+
+impl<'a, R, T: TableDeserialize, O: ORMTrait<O>> Aggregate<'a, R, T, O> {
+    pub(crate) fn new(orm: &'a O, function: &'static str, column: Option<&str>) -> Self {
+        Aggregate {
+            orm,
+            function,
+            column: column.map(|column| column.to_string()),
+            filter: None,
+            group_by: None,
+            result: PhantomData,
+            entity: PhantomData,
+        }
+    }
+
+    // Adds a condition, as in `find_many`; several filters all have to hold.
+    pub fn filter(mut self, query_where: &str) -> Self {
+        self.filter = Some(match self.filter {
+            Some(filter) => format!("{filter}) and ({query_where}"),
+            None => query_where.to_string(),
+        });
+        self
+    }
+
+    pub fn sql(&self) -> Result<String, ORMError> {
+        let table_name = O::quote_table(T::same_schema(), T::same_name().as_str());
+        let argument = match &self.column {
+            Some(column) => column_of::<T, O>(column)?,
+            None => "*".to_string(),
+        };
+        let mut conditions: Vec<String> = O::not_deleted::<T>().into_iter().collect();
+        if let Some(filter) = &self.filter {
+            conditions.push(format!("({filter})"));
+        }
+        let condition = match conditions.is_empty() {
+            true => String::new(),
+            false => format!(" where {}", conditions.join(" and ")),
+        };
+        let query = match (&self.group_by, self.function) {
+            (_, "exists") => format!("select 1 from {table_name}{condition} limit 1"),
+            (Some(key), function) => {
+                let key = column_of::<T, O>(key)?;
+                format!("select {key}, {function}({argument}) from {table_name}{condition} group by {key}")
+            }
+            (None, function) => format!("select {function}({argument}) from {table_name}{condition}"),
+        };
+        Ok(query)
+    }
+}
+
+impl<'a, K, T: TableDeserialize, O: ORMTrait<O>> GroupBy<'a, K, T, O> {
+    pub(crate) fn new(orm: &'a O, column: &str) -> Self {
+        GroupBy {
+            orm,
+            column: column.to_string(),
+            filter: None,
+            key: PhantomData,
+            entity: PhantomData,
+        }
+    }
+
+    // Adds a condition on the rows before they are grouped.
+    pub fn filter(mut self, query_where: &str) -> Self {
+        self.filter = Some(match self.filter {
+            Some(filter) => format!("{filter}) and ({query_where}"),
+            None => query_where.to_string(),
+        });
+        self
+    }
+
+    // The number of rows of each value of the column.
+    pub fn count(self) -> Aggregate<'a, Vec<(K, i64)>, T, O> {
+        let mut aggregate = Aggregate::new(self.orm, "count", None);
+        aggregate.filter = self.filter;
+        aggregate.group_by = Some(self.column);
+        aggregate
+    }
+}
+
+fn column_of<T: TableDeserialize, O: ORMTrait<O>>(column: &str) -> Result<String, ORMError> {
+    match T::fields().iter().any(|field| field == column) {
+        true => Ok(O::quote(column)),
+        false => Err(ORMError::InvalidValue(format!("no column {} in {}", column, T::same_name()))),
+    }
+}
+
+fn parse<V: FromStr>(row: &Row, index: i32) -> Result<Option<V>, ORMError> {
+    match row.columns.get(&index) {
+        Some(Some(value)) => V::from_str(value.as_str())
+            .map(Some)
+            .map_err(|_| ORMError::InvalidValue(value.clone())),
+        _ => Ok(None),
+    }
+}
+
+pub(crate) fn count(rows: Vec<Row>) -> Result<i64, ORMError> {
+    match rows.first() {
+        Some(row) => Ok(parse(row, 0)?.unwrap_or(0)),
+        None => Ok(0),
+    }
+}
+
+// `None` for the `sum`, `avg`, `min` and `max` of no rows.
+pub(crate) fn value<V: FromStr>(rows: Vec<Row>) -> Result<Option<V>, ORMError> {
+    match rows.first() {
+        Some(row) => parse(row, 0),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn groups<K: FromStr>(rows: Vec<Row>) -> Result<Vec<(K, i64)>, ORMError> {
+    let mut groups = Vec::new();
+    for row in rows {
+        let key = parse(&row, 0)?.ok_or_else(|| ORMError::InvalidValue("null group key".to_string()))?;
+        groups.push((key, parse(&row, 1)?.unwrap_or(0)));
+    }
+    Ok(groups)
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::sqlite::ORM;
+
+    #[derive(Debug)]
+    struct User;
+
+    impl TableDeserialize for User {
+        fn same_name() -> String {
+            "user".to_string()
+        }
+        fn fields() -> Vec<String> {
+            vec!["id".to_string(), "age".to_string(), "country".to_string()]
+        }
+        fn soft_delete() -> Option<String> {
+            Some("deleted_at".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sql() -> Result<(), ORMError> {
+        let orm = ORM::connect(":memory:".to_string())?;
+        let sum = Aggregate::<Option<i64>, User, ORM>::new(&orm, "sum", Some("age")).filter("age > 1").filter("id < 9");
+        assert_eq!(sum.sql()?, "select sum(\"age\") from \"user\" where \"deleted_at\" is null and (age > 1) and (id < 9)");
+        let exists = Aggregate::<bool, User, ORM>::new(&orm, "exists", None);
+        assert_eq!(exists.sql()?, "select 1 from \"user\" where \"deleted_at\" is null limit 1");
+        let groups = GroupBy::<String, User, ORM>::new(&orm, "country").count();
+        assert_eq!(groups.sql()?, "select \"country\", count(*) from \"user\" where \"deleted_at\" is null group by \"country\"");
+        assert!(Aggregate::<Option<i64>, User, ORM>::new(&orm, "max", Some("name")).sql().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+// The query cache of an ORM, off until `enable_cache`: the rows of the
+// `find_one`, `find_many` and `find_all` queries are kept by their SQL and the
+// values of its placeholders for a time to live, up to a number of entries.
+//
+// Every statement which the ORM writes with drops the entries which read a
+// table it names, before and after it runs, and a rollback drops them all.
+// Writes by other connections are only seen once the entries expire.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::{Row, SqlValue};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheConfig {
+    pub ttl: Duration,
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            ttl: Duration::from_secs(60),
+            max_entries: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+#[derive(Debug)]
+pub(crate) struct QueryCache {
+    config: CacheConfig,
+    entries: HashMap<String, Entry>,
+    hits: u64,
+    misses: u64,
+    // counts the invalidations, so that the rows of a query which ran before
+    // one aren't kept
+    generation: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    // the names of the tables which the query reads, in lower case
+    tables: Vec<String>,
+    rows: Vec<Row>,
+    at: Instant,
+}
+
+// The words of a statement, which include the names of the tables it touches
+// whether they are quoted or not.
+fn words(statement: &str) -> impl Iterator<Item = String> + '_ {
+    statement.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+// The key of the rows of a query.
+fn key(query: &str, params: &[SqlValue]) -> String {
+    match params.is_empty() {
+        true => query.to_string(),
+        false => format!("{} {:?}", query, params),
+    }
+}
+
+// The tables which a query reads: the names after `from` and `join`, and
+// after the commas of the table list of a `from`, without their schema.
+fn tables(query: &str) -> Vec<String> {
+    let query = query.replace(['"', '`'], "").replace(',', " , ").replace('(', " ( ").replace(')', " ) ");
+    let mut tokens = query.split_whitespace().map(str::to_lowercase);
+    let mut tables = Vec::new();
+    // in the table list of a `from`
+    let mut listing = false;
+    while let Some(token) = tokens.next() {
+        match token.as_str() {
+            "from" | "join" => listing = true,
+            "," if listing => {}
+            "(" | ")" | "where" | "on" | "using" | "group" | "having" | "order" | "limit" | "union" => {
+                listing = false;
+                continue;
+            }
+            _ => continue,
+        }
+        if let Some(table) = tokens.next().filter(|table| table != "(") {
+            tables.push(table.rsplit('.').next().unwrap_or_default().to_string());
+        }
+    }
+    tables
+}
+
+impl QueryCache {
+    pub(crate) fn new(config: CacheConfig) -> Self {
+        QueryCache {
+            config,
+            entries: HashMap::new(),
+            hits: 0,
+            misses: 0,
+            generation: 0,
+        }
+    }
+
+    pub(crate) fn get(&mut self, query: &str, params: &[SqlValue]) -> Option<Vec<Row>> {
+        let ttl = self.config.ttl;
+        self.entries.retain(|_, entry| entry.at.elapsed() < ttl);
+        match self.entries.get(&key(query, params)) {
+            Some(entry) => {
+                self.hits += 1;
+                Some(entry.rows.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // Keeps the rows of a query which ran after the `generation`, unless a
+    // write has invalidated the cache since.
+    pub(crate) fn put(&mut self, query: &str, params: &[SqlValue], generation: u64, rows: Vec<Row>) {
+        if self.config.max_entries == 0 || generation != self.generation {
+            return;
+        }
+        while self.entries.len() >= self.config.max_entries {
+            let oldest = self.entries.iter().min_by_key(|(_, entry)| entry.at).map(|(query, _)| query.clone());
+            match oldest {
+                Some(oldest) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+        self.entries.insert(key(query, params), Entry {
+            tables: tables(query),
+            rows,
+            at: Instant::now(),
+        });
+    }
+
+    // Drops the entries which read a table that the statement names.
+    pub(crate) fn invalidate(&mut self, statement: &str) {
+        self.generation += 1;
+        let words: Vec<String> = words(statement).collect();
+        if words.first().is_some_and(|word| word == "rollback") {
+            self.entries.clear();
+            return;
+        }
+        self.entries.retain(|_, entry| !entry.tables.iter().any(|table| words.contains(table)));
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache() {
+        let mut cache = QueryCache::new(CacheConfig { ttl: Duration::from_secs(60), max_entries: 2 });
+        assert!(cache.get("select * from \"user\"", &[]).is_none());
+        cache.put("select * from \"user\"", &[], 0, vec![Row::new()]);
+        cache.put("select * from `Config` where id = 1", &[], 0, Vec::new());
+        assert_eq!(Some(1), cache.get("select * from \"user\"", &[]).map(|rows| rows.len()));
+        cache.invalidate("update `config` set value = 'x'");
+        assert!(cache.get("select * from `Config` where id = 1", &[]).is_none());
+        // a query which ran before the last write isn't kept
+        cache.put("select * from a", &[], 0, Vec::new());
+        assert!(cache.get("select * from a", &[]).is_none());
+        cache.put("select * from a", &[], cache.generation(), Vec::new());
+        cache.put("select * from b", &[], cache.generation(), Vec::new());
+        // the oldest entry made room
+        assert!(cache.get("select * from \"user\"", &[]).is_none());
+        cache.invalidate("insert into user_log (id) values (1)");
+        assert_eq!(2, cache.stats().entries);
+        cache.invalidate("ROLLBACK");
+        assert_eq!(CacheStats { hits: 1, misses: 4, entries: 0 }, cache.stats());
+
+        let mut cache = QueryCache::new(CacheConfig { ttl: Duration::ZERO, max_entries: 2 });
+        cache.put("select * from \"user\"", &[], 0, Vec::new());
+        assert!(cache.get("select * from \"user\"", &[]).is_none());
+
+        // a write to a joined table drops the entry too
+        let mut cache = QueryCache::new(CacheConfig::default());
+        cache.put("select * from \"order\" where user_id in (select id from \"user\" where age > 30)", &[], 0, Vec::new());
+        cache.put("select o.* from \"order\" o join \"user\" u on u.id = o.user_id", &[], 0, Vec::new());
+        cache.put("select * from \"order\"", &[], 0, Vec::new());
+        cache.invalidate("update \"user\" set age = 31");
+        assert_eq!(1, cache.stats().entries);
+
+        // the values of the placeholders are part of the key
+        cache.put("select * from a where id = ?", &[SqlValue::Integer(1)], cache.generation(), vec![Row::new()]);
+        assert!(cache.get("select * from a where id = ?", &[SqlValue::Integer(2)]).is_none());
+        assert!(cache.get("select * from a where id = ?", &[SqlValue::Integer(1)]).is_some());
+    }
+
+    #[test]
+    fn test_tables() {
+        assert_eq!(vec!["user"], tables("select * from \"main\".\"user\" where (id > 1)"));
+        assert_eq!(vec!["a", "b", "c"], tables("SELECT * FROM a x, b LEFT JOIN c ON c.id = b.c_id WHERE x.id IN (1, 2)"));
+        assert_eq!(vec!["a", "b"], tables("select * from a where id in (select a_id from b)"));
+    }
+}
@@ -0,0 +1,118 @@
+// The source of the current time for the `#[column(created_at)]` and
+// `#[column(updated_at)]` fields and for soft deletes.
+//
+// Timestamps are written in UTC like the date-time fields: as text
+// `YYYY-MM-DD HH:MM:SS[.f]`, or as unix seconds for `#[column(as_integer)]`
+// fields.
+
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// Always tells the same time, for deterministic tests.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+// The timestamp of `now`, unquoted.
+pub(crate) fn timestamp(now: SystemTime, as_integer: bool) -> String {
+    let (seconds, nanos) = unix(now);
+    if as_integer {
+        return seconds.to_string();
+    }
+    format!("{} {}", format_date(seconds), format_time(seconds, nanos))
+}
+
+// The unix seconds of `now`, and its nanoseconds within the second.
+pub(crate) fn unix(now: SystemTime) -> (i64, u32) {
+    match now.duration_since(UNIX_EPOCH) {
+        Ok(duration) => (duration.as_secs() as i64, duration.subsec_nanos()),
+        Err(e) => {
+            let duration = e.duration();
+            match duration.subsec_nanos() {
+                0 => (-(duration.as_secs() as i64), 0),
+                nanos => (-(duration.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
+        }
+    }
+}
+
+// Days since 1970-01-01 to a civil date, by the date-time crate of the
+// features. Dates out of its range, which no clock reaches, are zero.
+#[cfg(feature = "chrono")]
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    use chrono::Datelike;
+    match chrono::DateTime::from_timestamp(days.saturating_mul(86400), 0) {
+        Some(t) => (i64::from(t.year()), t.month(), t.day()),
+        None => (0, 0, 0),
+    }
+}
+
+#[cfg(all(feature = "time", not(feature = "chrono")))]
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    match time::OffsetDateTime::from_unix_timestamp(days.saturating_mul(86400)) {
+        Ok(t) => (i64::from(t.year()), u8::from(t.month()) as u32, u32::from(t.day())),
+        Err(_) => (0, 0, 0),
+    }
+}
+
+// From Howard Hinnant's `civil_from_days`, without a date-time crate.
+#[cfg(not(any(feature = "chrono", feature = "time")))]
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+pub(crate) fn format_date(seconds: i64) -> String {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+pub(crate) fn format_time(seconds: i64, nanos: u32) -> String {
+    let seconds = seconds.rem_euclid(86400);
+    let time = format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if nanos == 0 {
+        return time;
+    }
+    let fraction = format!("{:09}", nanos);
+    format!("{}.{}", time, fraction.trim_end_matches('0'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use super::timestamp;
+
+    #[test]
+    fn test_timestamp() {
+        let now = UNIX_EPOCH + Duration::new(1700000000, 500000000);
+        assert_eq!("2023-11-14 22:13:20.5", timestamp(now, false));
+        assert_eq!("1700000000", timestamp(now, true));
+        assert_eq!("1969-12-31 23:59:59.75", timestamp(UNIX_EPOCH - Duration::from_millis(250), false));
+    }
+}
@@ -0,0 +1,332 @@
+// Date and time fields of the `chrono` and `time` crates.
+//
+// Instants are stored in UTC, either as text `YYYY-MM-DD HH:MM:SS[.f]` which
+// fits SQLite TEXT and MySQL DATETIME/TIMESTAMP columns, or as unix seconds for
+// `#[column(as_integer)]` fields. Dates are stored as `YYYY-MM-DD` (midnight
+// UTC in unix seconds) and times as `HH:MM:SS[.f]` (seconds since midnight).
+//
+// Values are converted from and to their serde form, so the entity fields keep
+// using the plain `Serialize`/`Deserialize` impls of those crates.
+
+use crate::clock::{format_date, format_time};
+use crate::serializer_error::{Error, Result};
+use crate::SqlValue;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DateTimeKind {
+    #[cfg(feature = "chrono")]
+    ChronoDateTime,
+    #[cfg(feature = "chrono")]
+    ChronoNaiveDateTime,
+    #[cfg(feature = "chrono")]
+    ChronoNaiveDate,
+    #[cfg(feature = "chrono")]
+    ChronoNaiveTime,
+    #[cfg(feature = "time")]
+    OffsetDateTime,
+    #[cfg(feature = "time")]
+    PrimitiveDateTime,
+    #[cfg(feature = "time")]
+    Date,
+    #[cfg(feature = "time")]
+    Time,
+}
+
+enum Shape {
+    DateTime,
+    Date,
+    Time,
+}
+
+impl DateTimeKind {
+    fn shape(&self) -> Shape {
+        match self {
+            #[cfg(feature = "chrono")]
+            DateTimeKind::ChronoDateTime | DateTimeKind::ChronoNaiveDateTime => Shape::DateTime,
+            #[cfg(feature = "chrono")]
+            DateTimeKind::ChronoNaiveDate => Shape::Date,
+            #[cfg(feature = "chrono")]
+            DateTimeKind::ChronoNaiveTime => Shape::Time,
+            #[cfg(feature = "time")]
+            DateTimeKind::OffsetDateTime | DateTimeKind::PrimitiveDateTime => Shape::DateTime,
+            #[cfg(feature = "time")]
+            DateTimeKind::Date => Shape::Date,
+            #[cfg(feature = "time")]
+            DateTimeKind::Time => Shape::Time,
+        }
+    }
+}
+
+// Recognizes the date-time types by the Rust type of the field, as recorded by
+// the derive, e.g. `Option<chrono::DateTime<Utc>>`.
+pub fn kind(rust_type: &str) -> Option<DateTimeKind> {
+    let mut ty: String = rust_type.chars().filter(|c| !c.is_whitespace()).collect();
+    if let Some(inner) = ty.strip_prefix("Option<").and_then(|t| t.strip_suffix('>')) {
+        ty = inner.to_string();
+    }
+    let path = ty.split('<').next().unwrap_or_default();
+    let name = path.rsplit("::").next().unwrap_or_default();
+    match name {
+        #[cfg(feature = "chrono")]
+        "DateTime" => Some(DateTimeKind::ChronoDateTime),
+        #[cfg(feature = "chrono")]
+        "NaiveDateTime" => Some(DateTimeKind::ChronoNaiveDateTime),
+        #[cfg(feature = "chrono")]
+        "NaiveDate" => Some(DateTimeKind::ChronoNaiveDate),
+        #[cfg(feature = "chrono")]
+        "NaiveTime" => Some(DateTimeKind::ChronoNaiveTime),
+        #[cfg(feature = "time")]
+        "OffsetDateTime" => Some(DateTimeKind::OffsetDateTime),
+        #[cfg(feature = "time")]
+        "PrimitiveDateTime" => Some(DateTimeKind::PrimitiveDateTime),
+        // Too common names to be taken without the crate path.
+        #[cfg(feature = "time")]
+        "Date" if path.ends_with("time::Date") => Some(DateTimeKind::Date),
+        #[cfg(feature = "time")]
+        "Time" if path.ends_with("time::Time") => Some(DateTimeKind::Time),
+        _ => None,
+    }
+}
+
+// Converts the serde form of a field into the value of its column.
+pub fn to_sql(kind: DateTimeKind, value: serde_json::Value, as_integer: bool) -> Result<SqlValue> {
+    if value.is_null() {
+        return Ok(SqlValue::Null);
+    }
+    let (seconds, nanos) = to_unix(kind, value)?;
+    if as_integer {
+        return Ok(SqlValue::Integer(seconds));
+    }
+    let text = match kind.shape() {
+        Shape::DateTime => format!("{} {}", format_date(seconds), format_time(seconds, nanos)),
+        Shape::Date => format_date(seconds),
+        Shape::Time => format_time(seconds, nanos),
+    };
+    Ok(SqlValue::Text(text))
+}
+
+// Converts a value read from a column into the serde form of the field.
+pub fn from_sql(kind: DateTimeKind, value: &str) -> Result<serde_json::Value> {
+    let (seconds, nanos) = match kind.shape() {
+        Shape::Time => parse_time(value),
+        Shape::DateTime | Shape::Date => parse_datetime(value),
+    }.ok_or_else(|| Error::Message(format!("invalid date-time: {}", value)))?;
+    from_unix(kind, seconds, nanos)
+}
+
+fn invalid() -> Error {
+    Error::Message("date-time out of range".to_string())
+}
+
+fn to_unix(kind: DateTimeKind, value: serde_json::Value) -> Result<(i64, u32)> {
+    match kind {
+        #[cfg(feature = "chrono")]
+        DateTimeKind::ChronoDateTime => {
+            let t: chrono::DateTime<chrono::FixedOffset> = serde_json::from_value(value)?;
+            Ok((t.timestamp(), t.timestamp_subsec_nanos()))
+        }
+        #[cfg(feature = "chrono")]
+        DateTimeKind::ChronoNaiveDateTime => {
+            let t: chrono::NaiveDateTime = serde_json::from_value(value)?;
+            Ok((t.and_utc().timestamp(), t.and_utc().timestamp_subsec_nanos()))
+        }
+        #[cfg(feature = "chrono")]
+        DateTimeKind::ChronoNaiveDate => {
+            let d: chrono::NaiveDate = serde_json::from_value(value)?;
+            Ok((d.and_time(chrono::NaiveTime::MIN).and_utc().timestamp(), 0))
+        }
+        #[cfg(feature = "chrono")]
+        DateTimeKind::ChronoNaiveTime => {
+            use chrono::Timelike;
+            let t: chrono::NaiveTime = serde_json::from_value(value)?;
+            Ok((i64::from(t.num_seconds_from_midnight()), t.nanosecond()))
+        }
+        #[cfg(feature = "time")]
+        DateTimeKind::OffsetDateTime => {
+            let t: time::OffsetDateTime = serde_json::from_value(value)?;
+            Ok((t.unix_timestamp(), t.nanosecond()))
+        }
+        #[cfg(feature = "time")]
+        DateTimeKind::PrimitiveDateTime => {
+            let t: time::PrimitiveDateTime = serde_json::from_value(value)?;
+            Ok((t.assume_utc().unix_timestamp(), t.nanosecond()))
+        }
+        #[cfg(feature = "time")]
+        DateTimeKind::Date => {
+            let d: time::Date = serde_json::from_value(value)?;
+            Ok((d.midnight().assume_utc().unix_timestamp(), 0))
+        }
+        #[cfg(feature = "time")]
+        DateTimeKind::Time => {
+            let t: time::Time = serde_json::from_value(value)?;
+            let (hour, minute, second) = t.as_hms();
+            Ok((i64::from(hour) * 3600 + i64::from(minute) * 60 + i64::from(second), t.nanosecond()))
+        }
+    }
+}
+
+fn from_unix(kind: DateTimeKind, seconds: i64, nanos: u32) -> Result<serde_json::Value> {
+    let value = match kind {
+        #[cfg(feature = "chrono")]
+        DateTimeKind::ChronoDateTime => {
+            serde_json::to_value(chrono::DateTime::from_timestamp(seconds, nanos).ok_or_else(invalid)?)?
+        }
+        #[cfg(feature = "chrono")]
+        DateTimeKind::ChronoNaiveDateTime => {
+            serde_json::to_value(chrono::DateTime::from_timestamp(seconds, nanos).ok_or_else(invalid)?.naive_utc())?
+        }
+        #[cfg(feature = "chrono")]
+        DateTimeKind::ChronoNaiveDate => {
+            serde_json::to_value(chrono::DateTime::from_timestamp(seconds, 0).ok_or_else(invalid)?.date_naive())?
+        }
+        #[cfg(feature = "chrono")]
+        DateTimeKind::ChronoNaiveTime => {
+            let seconds = u32::try_from(seconds).map_err(|_| invalid())?;
+            serde_json::to_value(chrono::NaiveTime::from_num_seconds_from_midnight_opt(seconds, nanos).ok_or_else(invalid)?)?
+        }
+        #[cfg(feature = "time")]
+        DateTimeKind::OffsetDateTime => {
+            serde_json::to_value(offset_date_time(seconds, nanos)?)?
+        }
+        #[cfg(feature = "time")]
+        DateTimeKind::PrimitiveDateTime => {
+            let t = offset_date_time(seconds, nanos)?;
+            serde_json::to_value(time::PrimitiveDateTime::new(t.date(), t.time()))?
+        }
+        #[cfg(feature = "time")]
+        DateTimeKind::Date => {
+            serde_json::to_value(offset_date_time(seconds, 0)?.date())?
+        }
+        #[cfg(feature = "time")]
+        DateTimeKind::Time => {
+            let hour = u8::try_from(seconds / 3600).map_err(|_| invalid())?;
+            let t = time::Time::from_hms_nano(hour, (seconds / 60 % 60) as u8, (seconds % 60) as u8, nanos)
+                .map_err(|_| invalid())?;
+            serde_json::to_value(t)?
+        }
+    };
+    Ok(value)
+}
+
+#[cfg(feature = "time")]
+fn offset_date_time(seconds: i64, nanos: u32) -> Result<time::OffsetDateTime> {
+    time::OffsetDateTime::from_unix_timestamp(seconds)
+        .and_then(|t| t.replace_nanosecond(nanos))
+        .map_err(|_| invalid())
+}
+
+// A civil date to days since 1970-01-01, none when there is no such date,
+// e.g. 2023-02-31.
+#[cfg(feature = "chrono")]
+fn days_from_civil(year: i64, month: u32, day: u32) -> Option<i64> {
+    let date = chrono::NaiveDate::from_ymd_opt(i32::try_from(year).ok()?, month, day)?;
+    Some(date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp() / 86400)
+}
+
+#[cfg(all(feature = "time", not(feature = "chrono")))]
+fn days_from_civil(year: i64, month: u32, day: u32) -> Option<i64> {
+    let month = time::Month::try_from(u8::try_from(month).ok()?).ok()?;
+    let date = time::Date::from_calendar_date(i32::try_from(year).ok()?, month, u8::try_from(day).ok()?).ok()?;
+    Some(date.midnight().assume_utc().unix_timestamp() / 86400)
+}
+
+// Parses `HH:MM:SS[.f]` or seconds since midnight.
+fn parse_time(value: &str) -> Option<(i64, u32)> {
+    if let Ok(seconds) = value.parse::<i64>() {
+        return Some((seconds, 0));
+    }
+    let (hms, fraction) = match value.split_once('.') {
+        Some((hms, fraction)) => (hms, fraction),
+        None => (value, ""),
+    };
+    let mut parts = hms.split(':');
+    let hour: i64 = parts.next()?.parse().ok()?;
+    let minute: i64 = parts.next()?.parse().ok()?;
+    let second: i64 = parts.next().unwrap_or("0").parse().ok()?;
+    if parts.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    Some((hour * 3600 + minute * 60 + second, parse_nanos(fraction)?))
+}
+
+fn parse_nanos(fraction: &str) -> Option<u32> {
+    if fraction.is_empty() {
+        return Some(0);
+    }
+    if fraction.len() > 9 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    format!("{:0<9}", fraction).parse().ok()
+}
+
+// Parses `YYYY-MM-DD`, `YYYY-MM-DD HH:MM:SS[.f]` with an optional `T`
+// separator and `Z` or `+HH:MM` suffix, or unix seconds.
+fn parse_datetime(value: &str) -> Option<(i64, u32)> {
+    if let Ok(seconds) = value.parse::<i64>() {
+        return Some((seconds, 0));
+    }
+    let value = value.trim();
+    let (date, rest) = match value.find([' ', 'T', 't']) {
+        Some(index) => (&value[..index], value[index + 1..].trim()),
+        None => (value, ""),
+    };
+    let mut parts = date.splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: u32 = parts.next()?.parse().ok()?;
+    let day: u32 = parts.next()?.parse().ok()?;
+    let days = days_from_civil(year, month, day)?;
+    if rest.is_empty() {
+        return Some((days * 86400, 0));
+    }
+    let (time, offset) = match rest.find(['Z', 'z', '+', '-']) {
+        Some(index) => (rest[..index].trim(), &rest[index..]),
+        None => (rest, ""),
+    };
+    let (seconds, nanos) = parse_time(time)?;
+    Some((days * 86400 + seconds - parse_offset(offset)?, nanos))
+}
+
+// Parses `Z`, `+HH:MM`, `+HH:MM:SS` or `+HHMM` into seconds east of UTC.
+fn parse_offset(offset: &str) -> Option<i64> {
+    if offset.is_empty() || offset.eq_ignore_ascii_case("z") {
+        return Some(0);
+    }
+    let sign = if offset.starts_with('-') { -1 } else { 1 };
+    let digits: String = offset[1..].chars().filter(|c| *c != ':').collect();
+    if digits.len() < 2 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i64 = digits[0..2].parse().ok()?;
+    let minutes: i64 = digits.get(2..4).unwrap_or("0").parse().ok()?;
+    let seconds: i64 = digits.get(4..6).unwrap_or("0").parse().ok()?;
+    Some(sign * (hours * 3600 + minutes * 60 + seconds))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::{format_date, format_time, parse_datetime};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Some((0, 0)), parse_datetime("1970-01-01"));
+        assert_eq!(Some((1700000000, 0)), parse_datetime("2023-11-14 22:13:20"));
+        assert_eq!(Some((1700000000, 500000000)), parse_datetime("2023-11-14T22:13:20.5Z"));
+        assert_eq!(Some((1700000000, 0)), parse_datetime("2023-11-15T00:13:20+02:00"));
+        assert_eq!(Some((1700000000, 0)), parse_datetime("1700000000"));
+        assert_eq!(Some((-86400, 0)), parse_datetime("1969-12-31"));
+        assert_eq!(None, parse_datetime("2023-13-01"));
+        assert_eq!(None, parse_datetime("2023-02-31"));
+        assert_eq!(Some((1709164800, 0)), parse_datetime("2024-02-29"));
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!("2023-11-14", format_date(1700000000));
+        assert_eq!("22:13:20", format_time(1700000000, 0));
+        assert_eq!("22:13:20.25", format_time(1700000000, 250000000));
+        assert_eq!("1969-12-31", format_date(-1));
+        assert_eq!("23:59:59", format_time(-1, 0));
+    }
+}
@@ -7,6 +7,7 @@
 // except according to those terms.
 
 use crate::serializer_error::{Error, Result};
+use crate::{Column, SqlValue};
 use serde::de::{
     self, Deserialize, DeserializeSeed, EnumAccess, IntoDeserializer,
     MapAccess, SeqAccess, VariantAccess, Visitor,
@@ -17,6 +18,12 @@ pub struct Deserializer<'de> {
     // This string starts with the input data and characters are truncated off
     // the beginning as data is parsed.
     input: &'de str,
+    // Column options of the fields of the top level struct.
+    columns: Vec<Column>,
+    // Set while the next map value belongs to one of `columns`.
+    column: Option<Column>,
+    // Set while the value of an `#[column(as_integer)]` field is parsed.
+    as_integer: bool,
 }
 
 impl<'de> Deserializer<'de> {
@@ -26,7 +33,7 @@ impl<'de> Deserializer<'de> {
     // deserializer can make one with `serde_json::Deserializer::from_str(...)`.
     #[allow(clippy::should_implement_trait)]
     pub fn from_str(input: &'de str) -> Self {
-        Deserializer { input }
+        Deserializer { input, columns: Vec::new(), column: None, as_integer: false }
     }
 }
 
@@ -35,11 +42,15 @@ impl<'de> Deserializer<'de> {
 // depending on what Rust types the deserializer is able to consume as input.
 //
 // This basic deserializer supports only `from_str`.
-pub fn from_str<'a, T>(s: &'a str) -> Result<T>
+//
+// The column options of the entity tell which fields hold JSON text or
+// date-times.
+pub fn from_str<'a, T>(s: &'a str, columns: Vec<Column>) -> Result<T>
     where
         T: Deserialize<'a>,
 {
     let mut deserializer = Deserializer::from_str(s);
+    deserializer.columns = columns;
     let t = T::deserialize(&mut deserializer)?;
     if deserializer.input.is_empty() {
         Ok(t)
@@ -187,6 +198,37 @@ impl<'de> Deserializer<'de> {
         // println!("fixed_r: {}", fixed_r);
         Ok(fixed_r)
     }
+
+    // Parse a string holding JSON text, as written for nested values.
+    fn parse_json(&mut self) -> Result<serde_json::Value> {
+        let s = self.parse_string()?;
+        Ok(serde_json::from_str(s.as_str())?)
+    }
+
+    // Parse the value of a field whose column says more than its serde type:
+    // fields with a codec, date-times, UUIDs and decimals in their column
+    // storage and `#[column(json)]` fields which are JSON text whatever their
+    // type. `None` leaves the input untouched.
+    fn parse_column(&mut self, column: &Column) -> Result<Option<serde_json::Value>> {
+        if let Some(codec) = column.codec {
+            let value = self.parse_string()?;
+            return (codec.from_sql)(SqlValue::Text(value)).map(Some).map_err(|e| Error::Message(e.to_string()));
+        }
+        #[cfg(any(feature = "chrono", feature = "time"))]
+        if let Some(kind) = crate::datetime::kind(column.rust_type.as_str()) {
+            let value = self.parse_string()?;
+            return crate::datetime::from_sql(kind, value.as_str()).map(Some);
+        }
+        #[cfg(any(feature = "uuid", feature = "rust_decimal"))]
+        if let Some(kind) = crate::scalar::kind(column.rust_type.as_str()) {
+            let value = self.parse_string()?;
+            return crate::scalar::from_sql(kind, value.as_str()).map(Some);
+        }
+        if column.json {
+            return self.parse_json().map(Some);
+        }
+        Ok(None)
+    }
 }
 
 impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
@@ -414,6 +456,10 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         where
             V: Visitor<'de>,
     {
+        // Nested sequences are stored as JSON text.
+        if self.peek_char()? == '"' {
+            return de::Deserializer::deserialize_seq(self.parse_json()?, visitor).map_err(Error::from);
+        }
         // Parse the opening bracket of the sequence.
         if self.next_char()? == '[' {
             // Give the visitor access to each element of the sequence.
@@ -462,6 +508,10 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         where
             V: Visitor<'de>,
     {
+        // Nested maps are stored as JSON text.
+        if self.peek_char()? == '"' {
+            return de::Deserializer::deserialize_map(self.parse_json()?, visitor).map_err(Error::from);
+        }
         // Parse the opening brace of the map.
         if self.next_char()? == '{' {
             // Give the visitor access to each entry of the map.
@@ -485,28 +535,44 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     // the fields cannot be known ahead of time is probably a map.
     fn deserialize_struct<V>(
         self,
-        _name: &'static str,
-        _fields: &'static [&'static str],
+        name: &'static str,
+        fields: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value>
         where
             V: Visitor<'de>,
     {
+        // Nested structs are stored as JSON text.
+        if self.peek_char()? == '"' {
+            return de::Deserializer::deserialize_struct(self.parse_json()?, name, fields, visitor).map_err(Error::from);
+        }
         self.deserialize_map(visitor)
     }
 
+    // Columns hold the variant name, the variant index for
+    // `#[column(as_integer)]` fields, or JSON text for data-carrying variants.
     fn deserialize_enum<V>(
         self,
-        _name: &'static str,
-        _variants: &'static [&'static str],
+        name: &'static str,
+        variants: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value>
         where
             V: Visitor<'de>,
     {
         if self.peek_char()? == '"' {
+            let value = self.parse_string()?;
+            if value.starts_with('{') {
+                let json: serde_json::Value = serde_json::from_str(value.as_str())?;
+                return de::Deserializer::deserialize_enum(json, name, variants, visitor).map_err(Error::from);
+            }
+            if self.as_integer && !variants.contains(&value.as_str()) {
+                if let Ok(index) = value.parse::<u32>() {
+                    return visitor.visit_enum(index.into_deserializer());
+                }
+            }
             // Visit a unit variant.
-            visitor.visit_enum(self.parse_string()?.into_deserializer())
+            visitor.visit_enum(value.into_deserializer())
         } else if self.next_char()? == '{' {
             // Visit a newtype variant, tuple variant, or struct variant.
             let value = visitor.visit_enum(Enum::new(self))?;
@@ -607,6 +673,10 @@ impl<'de, 'a> MapAccess<'de> for CommaSeparated<'a, 'de> {
             return Err(Error::ExpectedMapComma);
         }
         self.first = false;
+        let input = self.de.input;
+        self.de.column = self.de.columns.iter()
+            .find(|column| input.starts_with(format!("\"{}\":", column.name).as_str()))
+            .cloned();
         // Deserialize a map key.
         seed.deserialize(&mut *self.de).map(Some)
     }
@@ -621,8 +691,20 @@ impl<'de, 'a> MapAccess<'de> for CommaSeparated<'a, 'de> {
         if self.de.next_char()? != ':' {
             return Err(Error::ExpectedMapColon);
         }
+        let mut as_integer = false;
+        if let Some(column) = self.de.column.take() {
+            if self.de.peek_char()? == '"' {
+                if let Some(value) = self.de.parse_column(&column)? {
+                    return seed.deserialize(value).map_err(Error::from);
+                }
+            }
+            as_integer = column.as_integer;
+        }
         // Deserialize a map value.
-        seed.deserialize(&mut *self.de)
+        self.de.as_integer = as_integer;
+        let value = seed.deserialize(&mut *self.de);
+        self.de.as_integer = false;
+        value
     }
 }
 
@@ -735,7 +817,7 @@ mod tests {
             pub content: Option<String>,
         }
         let j = r#"{"id":"25","path":"C:\\ODS\\~reserved.txt","internal":null,"disk":"C","size":"0","modified":"0","content":"  "}"#;
-        let r: FileDescription = from_str(j).unwrap();
+        let r: FileDescription = from_str(j, Vec::new()).unwrap();
         let j = r#"{"id":"-222","id_positive":"1","name":"a\"
 \\","ud":"777"}"#;
         let expected = Test {
@@ -745,10 +827,10 @@ mod tests {
             ud: 777,
         };
         println!("{:?}", expected);
-                    let r: Test = from_str(j).unwrap();
+                    let r: Test = from_str(j, Vec::new()).unwrap();
         println!("{:?}", r);
 
-        assert_eq!(expected, from_str(j).unwrap());
+        assert_eq!(expected, from_str(j, Vec::new()).unwrap());
     }
 
 
@@ -772,10 +854,84 @@ mod tests {
             ud: 777,
         };
         println!("{:?}", expected);
-        let r: Test = from_str(j).unwrap();
+        let r: Test = from_str(j, Vec::new()).unwrap();
         println!("{:?}", r);
 
-        assert_eq!(expected, from_str(j).unwrap());
+        assert_eq!(expected, from_str(j, Vec::new()).unwrap());
+    }
+
+    #[test]
+    fn test_enum() {
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum Status {
+            Active,
+            Blocked,
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum Shape {
+            Circle(u32),
+            Rect { w: u32, h: u32 },
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Test {
+            id: i32,
+            status: Status,
+            level: Status,
+            shape: Shape,
+            other: Option<Shape>,
+        }
+
+        let j = r#"{"id":"1","status":"Blocked","level":"0","shape":"{\"Circle\":5}","other":"{\"Rect\":{\"h\":2,\"w\":1}}"}"#;
+        let expected = Test {
+            id: 1,
+            status: Status::Blocked,
+            level: Status::Active,
+            shape: Shape::Circle(5),
+            other: Some(Shape::Rect { w: 1, h: 2 }),
+        };
+
+        let mut level = crate::Column::new("level");
+        level.as_integer = true;
+        assert_eq!(expected, from_str(j, vec![level]).unwrap());
+        // an index is only taken for `#[column(as_integer)]` fields
+        assert!(from_str::<Test>(j, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_json() {
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Address {
+            city: String,
+            zip: Option<u32>,
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Test {
+            id: i32,
+            tags: Vec<String>,
+            scores: std::collections::HashMap<String, i64>,
+            address: Address,
+            note: String,
+            extra: Option<Vec<f64>>,
+        }
+
+        let j = r#"{"id":"1","tags":"[\"a\",\"b\\\"c\"]","scores":"{\"x\":-5}","address":"{\"city\":\"Oslo\",\"zip\":null}","note":"\"plain\"","extra":null}"#;
+        let expected = Test {
+            id: 1,
+            tags: vec!["a".to_string(), "b\"c".to_string()],
+            scores: vec![("x".to_string(), -5)].into_iter().collect(),
+            address: Address { city: "Oslo".to_string(), zip: None },
+            note: "plain".to_string(),
+            extra: None,
+        };
+
+        let mut note = crate::Column::new("note");
+        note.json = true;
+        assert_eq!(expected, from_str(j, vec![note]).unwrap());
     }
 
     // #[test]
@@ -1161,7 +1317,7 @@ mod tests {
         }
         let new_str = str.replace("\n", "\\n");
         println!("{}", new_str);
-        let r: FileDescription = from_str(new_str.as_str()).unwrap();
+        let r: FileDescription = from_str(new_str.as_str(), Vec::new()).unwrap();
 
     }
 }
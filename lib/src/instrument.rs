@@ -0,0 +1,257 @@
+// Instrumentation of the statements which an ORM executes: each is logged
+// with its operation, table, number of rows, duration and error, at debug
+// level, or at warn level when it is slower than the slow query threshold.
+//
+// With the `tracing` feature each statement is a `tracing` span with these
+// fields, and the events are `tracing` events; without it they go to `log`.
+//
+// In redaction mode the SQL is logged with `?` in place of its values, so
+// that user data doesn't end up in the logs.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::metrics::{MetricsSink, QueryEvent};
+use crate::ORMError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Instrumentation {
+    // statements which take longer are logged at warn level
+    pub slow_query: Option<Duration>,
+    // logs the SQL with `?` in place of the values
+    pub redact: bool,
+}
+
+pub(crate) struct QueryTimer {
+    slow_query: Option<Duration>,
+    sql: String,
+    operation: String,
+    table: String,
+    start: Instant,
+    backend: &'static str,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl Instrumentation {
+    pub(crate) fn start(&self, query: &str, backend: &'static str, metrics: Option<Arc<dyn MetricsSink>>) -> QueryTimer {
+        let sql = match self.redact {
+            true => redact(query),
+            false => query.to_string(),
+        };
+        let operation = operation(query);
+        let table = table(query).unwrap_or_default();
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "query",
+            operation = operation.as_str(),
+            table = table.as_str(),
+            sql = sql.as_str(),
+            rows = tracing::field::Empty,
+            duration_ms = tracing::field::Empty,
+            error = tracing::field::Empty,
+        );
+        QueryTimer {
+            slow_query: self.slow_query,
+            sql,
+            operation,
+            table,
+            start: Instant::now(),
+            backend,
+            metrics,
+            #[cfg(feature = "tracing")]
+            span,
+        }
+    }
+}
+
+impl QueryTimer {
+    // Takes the number of rows which the statement read or wrote, or its error.
+    pub(crate) fn finish(self, result: Result<usize, &ORMError>) {
+        let elapsed = self.start.elapsed();
+        let slow = self.slow_query.is_some_and(|slow_query| elapsed > slow_query);
+        let duration_ms = elapsed.as_secs_f64() * 1000.0;
+        if let Some(metrics) = &self.metrics {
+            metrics.query(&QueryEvent {
+                backend: self.backend,
+                operation: self.operation.clone(),
+                table: self.table.clone(),
+                latency: elapsed,
+                success: result.is_ok(),
+            });
+        }
+        #[cfg(feature = "tracing")]
+        {
+            self.span.record("duration_ms", duration_ms);
+            match &result {
+                Ok(rows) => self.span.record("rows", *rows),
+                Err(e) => self.span.record("error", tracing::field::display(e)),
+            };
+            match slow {
+                true => tracing::warn!(parent: &self.span, operation = self.operation.as_str(), table = self.table.as_str(),
+                    sql = self.sql.as_str(), duration_ms, "slow query"),
+                false => tracing::debug!(parent: &self.span, "query"),
+            }
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            let outcome = match &result {
+                Ok(rows) => format!("rows={}", rows),
+                Err(e) => format!("error={}", e),
+            };
+            match slow {
+                true => log::warn!("slow query operation={} table={} {} duration_ms={:.3} {:?}",
+                    self.operation, self.table, outcome, duration_ms, self.sql),
+                false => log::debug!("operation={} table={} {} duration_ms={:.3} {:?}",
+                    self.operation, self.table, outcome, duration_ms, self.sql),
+            }
+        }
+    }
+}
+
+// The first word of the statement, e.g. `select`.
+fn operation(query: &str) -> String {
+    query.split_whitespace().next().unwrap_or_default().to_lowercase()
+}
+
+// The table after the first `from`, `into` or `update` which isn't followed by
+// a subquery, or the table of a `create`, `alter` or `drop table`.
+fn table(query: &str) -> Option<String> {
+    let words: Vec<&str> = query.split_whitespace().collect();
+    let name = match operation(query).as_str() {
+        "create" | "alter" | "drop" => words.iter()
+            .skip_while(|word| !word.eq_ignore_ascii_case("table"))
+            .skip(1)
+            .find(|word| !["if", "not", "exists"].contains(&word.to_lowercase().as_str()))?,
+        _ => words.windows(2)
+            .find(|pair| ["from", "into", "update"].contains(&pair[0].to_lowercase().as_str()) && !pair[1].starts_with('('))
+            .map(|pair| pair[1])?,
+    };
+    let name = name.split(['(', ')', ',', ';']).next().unwrap_or_default();
+    Some(name.chars().filter(|c| !matches!(c, '"' | '`')).collect())
+}
+
+// Replaces the literals of the statement by `?`: numbers, strings in single
+// quotes, and strings in double quotes where a value is expected, since SQLite
+// statements of the ORM quote both identifiers and values with them. When in
+// doubt a quoted identifier is taken as a value, so none leaks.
+pub(crate) fn redact(query: &str) -> String {
+    let chars: Vec<char> = query.chars().collect();
+    let mut output = String::new();
+    // the last keyword, identifier or punctuation, in lower case
+    let mut previous = String::new();
+    // for each open parenthesis, whether it holds a list of values
+    let mut lists: Vec<bool> = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let in_list = lists.last().copied().unwrap_or(false);
+        if c == '\'' || (matches!(c, 'x' | 'X') && chars.get(i + 1) == Some(&'\'') && !is_word(&previous_char(&chars, i))) {
+            i = skip_quoted(&chars, if c == '\'' { i } else { i + 1 });
+            output.push('?');
+            previous = "?".to_string();
+        } else if c == '"' {
+            let end = skip_quoted(&chars, i);
+            let value = in_list || matches!(previous.as_str(), "=" | "<" | ">" | "like" | "+" | "-" | "*" | "/");
+            match value {
+                true => output.push('?'),
+                false => output.extend(&chars[i..end]),
+            }
+            previous = if value { "?".to_string() } else { "\"".to_string() };
+            i = end;
+        } else if c == '`' {
+            let end = skip_quoted(&chars, i);
+            output.extend(&chars[i..end]);
+            previous = "`".to_string();
+            i = end;
+        } else if c.is_ascii_digit() && !is_word(&previous_char(&chars, i)) {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            output.push('?');
+            previous = "?".to_string();
+        } else if is_word(&Some(c)) {
+            let start = i;
+            while i < chars.len() && is_word(&Some(chars[i])) {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            output.push_str(word.as_str());
+            previous = word.to_lowercase();
+        } else {
+            match c {
+                '(' => lists.push(in_list || previous == "values" || previous == "in"),
+                ')' => _ = lists.pop(),
+                _ => {}
+            }
+            output.push(c);
+            if !c.is_whitespace() {
+                previous = c.to_string();
+            }
+            i += 1;
+        }
+    }
+    output
+}
+
+fn is_word(c: &Option<char>) -> bool {
+    c.is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '$')
+}
+
+fn previous_char(chars: &[char], i: usize) -> Option<char> {
+    i.checked_sub(1).map(|i| chars[i])
+}
+
+// The index after the quoted text which starts at `start`, where a doubled
+// quote stands for the quote itself.
+fn skip_quoted(chars: &[char], start: usize) -> usize {
+    let quote = chars[start];
+    let mut i = start + 1;
+    while i < chars.len() {
+        if chars[i] == '\\' && quote != '`' {
+            i += 2;
+            continue;
+        }
+        if chars[i] == quote {
+            if chars.get(i + 1) == Some(&quote) {
+                i += 2;
+                continue;
+            }
+            return i + 1;
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(redact(r#"insert into "user" ("name","age") values ("Jo""hn",30)"#), r#"insert into "user" ("name","age") values (?,?)"#);
+        assert_eq!(redact(r#"update "user" set "name" = "John","version" = "version" + 1 where "id" = 1"#),
+            r#"update "user" set "name" = ?,"version" = ? + ? where "id" = ?"#);
+        assert_eq!(redact("select * from `user` where name = 'O''Brien' and id in (1, 2) and `key` = X'0aff' limit 2"),
+            "select * from `user` where name = ? and id in (?, ?) and `key` = ? limit ?");
+        assert_eq!(redact(r#"select * from "file10" where json_extract("data", '$.a') >= -2.5e3"#),
+            r#"select * from "file10" where json_extract("data", ?) >= -?"#);
+        assert_eq!(redact("select * from t where name = 'it\\'s'"), "select * from t where name = ?");
+    }
+
+    #[test]
+    fn test_table() {
+        assert_eq!(Some("user".to_string()), table(r#"select * from "user" where "id" = 1"#));
+        assert_eq!(Some("main.order".to_string()), table(r#"insert into "main"."order" ("key") values ("A")"#));
+        assert_eq!(Some("citizen".to_string()), table(r#"select * from (select * from "citizen") as page"#));
+        assert_eq!(Some("note".to_string()), table("UPDATE `note` SET `deleted_at` = null"));
+        assert_eq!(None, table("begin"));
+        assert_eq!(Some("citizen".to_string()), table("CREATE TABLE IF NOT EXISTS citizen(id INTEGER PRIMARY KEY)"));
+        assert_eq!(Some("note".to_string()), table("alter table `note` add column `body` TEXT"));
+        assert_eq!(Some("profile".to_string()), table("drop table if exists profile;"));
+        assert_eq!(None, table("create index idx on citizen (age)"));
+        assert_eq!("select", operation("SELECT 1"));
+    }
+}
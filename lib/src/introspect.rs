@@ -0,0 +1,133 @@
+// Introspection of the schema of the database: `tables`, `columns`,
+// `indexes` and `foreign_keys` of a connection read it from the catalog of
+// each backend, `pragma_table_info` and friends on SQLite and
+// `information_schema` on MySQL.
+//
+//     for table in conn.tables().await? {
+//         let columns = conn.columns(table.as_str()).await?;
+//     }
+//
+// Names are compared as the catalog does, so a table which doesn't exist
+// simply has no columns, indexes or foreign keys.
+
+use crate::Row;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnInfo {
+    pub name: String,
+    // as declared, e.g. `varchar(255)`, empty when SQLite has none
+    pub declared_type: String,
+    pub nullable: bool,
+    // as the catalog has it, e.g. `'none'` on SQLite and `none` on MySQL
+    pub default: Option<String>,
+    pub primary_key: bool,
+    // whether the database generates the value, by AUTO_INCREMENT on MySQL
+    // and as the alias of the rowid on SQLite
+    pub auto_increment: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexInfo {
+    pub name: String,
+    // in the order of the index
+    pub columns: Vec<String>,
+    pub unique: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignKeyInfo {
+    // the name of the constraint on MySQL, its number on SQLite
+    pub name: String,
+    pub columns: Vec<String>,
+    pub referenced_table: String,
+    pub referenced_columns: Vec<String>,
+    pub on_update: String,
+    pub on_delete: String,
+}
+
+fn text(row: &Row, index: i32) -> String {
+    row.get::<String>(index).unwrap_or_default()
+}
+
+// The names in the first column of the rows.
+pub(crate) fn names(rows: Vec<Row>) -> Vec<String> {
+    rows.iter().map(|row| text(row, 0)).collect()
+}
+
+// Rows of name, unique and column, one per column of an index, in the order of
+// the index.
+pub(crate) fn indexes(rows: Vec<Row>) -> Vec<IndexInfo> {
+    let mut indexes: Vec<IndexInfo> = Vec::new();
+    for row in rows {
+        let name = text(&row, 0);
+        let column = text(&row, 2);
+        match indexes.last_mut() {
+            Some(index) if index.name == name => index.columns.push(column),
+            _ => indexes.push(IndexInfo {
+                name,
+                columns: vec![column],
+                unique: row.get::<i64>(1) == Some(1),
+            }),
+        }
+    }
+    indexes
+}
+
+// Rows of name, column, referenced table, referenced column, on update and on
+// delete, one per column of a foreign key, in the order of the key.
+pub(crate) fn foreign_keys(rows: Vec<Row>) -> Vec<ForeignKeyInfo> {
+    let mut keys: Vec<ForeignKeyInfo> = Vec::new();
+    for row in rows {
+        let name = text(&row, 0);
+        match keys.last_mut() {
+            Some(key) if key.name == name => {
+                key.columns.push(text(&row, 1));
+                key.referenced_columns.push(text(&row, 3));
+            }
+            _ => keys.push(ForeignKeyInfo {
+                name,
+                columns: vec![text(&row, 1)],
+                referenced_table: text(&row, 2),
+                referenced_columns: vec![text(&row, 3)],
+                on_update: text(&row, 4),
+                on_delete: text(&row, 5),
+            }),
+        }
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(values: &[Option<&str>]) -> Row {
+        let mut row = Row::new();
+        for (i, value) in values.iter().enumerate() {
+            row.set(i as i32, value.map(str::to_string));
+        }
+        row
+    }
+
+    #[test]
+    fn test_grouping() {
+        let rows = vec![
+            row(&[Some("idx_a"), Some("0"), Some("a")]),
+            row(&[Some("idx_a"), Some("0"), Some("b")]),
+            row(&[Some("uq_c"), Some("1"), Some("c")]),
+        ];
+        assert_eq!(vec![
+            IndexInfo { name: "idx_a".to_string(), columns: vec!["a".to_string(), "b".to_string()], unique: false },
+            IndexInfo { name: "uq_c".to_string(), columns: vec!["c".to_string()], unique: true },
+        ], indexes(rows));
+        let rows = vec![
+            row(&[Some("0"), Some("a"), Some("parent"), Some("x"), Some("NO ACTION"), Some("CASCADE")]),
+            row(&[Some("0"), Some("b"), Some("parent"), Some("y"), Some("NO ACTION"), Some("CASCADE")]),
+        ];
+        let keys = foreign_keys(rows);
+        assert_eq!(1, keys.len());
+        assert_eq!(vec!["a", "b"], keys[0].columns);
+        assert_eq!(vec!["x", "y"], keys[0].referenced_columns);
+        assert_eq!("CASCADE", keys[0].on_delete);
+    }
+}
@@ -4,6 +4,9 @@
 //! ## Usage
 //!  https://crates.io/crates/ormlib
 
+// Without a backend only the traits and helpers are left, with nothing to use
+// the internals of the backends.
+#![cfg_attr(not(any(feature = "sqlite", feature = "mysql")), allow(dead_code))]
 
 #[cfg(any(feature = "sqlite", feature = "mysql"))]
 mod serializer_error;
@@ -15,6 +18,27 @@ mod serializer_values;
 mod serializer_key_values;
 #[cfg(any(feature = "sqlite", feature = "mysql"))]
 mod deserializer_key_values;
+#[cfg(all(any(feature = "sqlite", feature = "mysql"), any(feature = "chrono", feature = "time")))]
+mod datetime;
+#[cfg(all(any(feature = "sqlite", feature = "mysql"), any(feature = "uuid", feature = "rust_decimal")))]
+mod scalar;
+
+pub mod sql_value;
+pub mod clock;
+pub mod validation;
+pub mod aggregate;
+pub mod pagination;
+pub mod tracking;
+#[cfg(any(feature = "sqlite", feature = "mysql"))]
+pub mod session;
+pub mod cache;
+pub mod instrument;
+pub mod metrics;
+pub mod retry;
+pub mod introspect;
+pub mod schema;
+pub mod migration;
+mod unit;
 
 #[cfg(feature = "sqlite")]
 pub mod sqlite;
@@ -31,6 +55,24 @@ use serde::{Deserialize, Serialize};
 
 use thiserror::Error;
 
+pub use sql_value::{FromSqlValue, SqlValue, ToSqlValue};
+pub use clock::{Clock, FixedClock, SystemClock};
+pub use validation::FieldError;
+pub use aggregate::{Aggregate, GroupBy};
+pub use pagination::{Cursor, Page, Paginate};
+pub use tracking::Tracked;
+#[cfg(any(feature = "sqlite", feature = "mysql"))]
+pub use session::Session;
+pub use cache::{CacheConfig, CacheStats};
+pub use instrument::Instrumentation;
+pub use metrics::{MetricsSink, QueryEvent};
+pub use retry::RetryPolicy;
+pub use introspect::{ColumnInfo, ForeignKeyInfo, IndexInfo};
+pub use schema::Mismatch;
+pub use migration::{AppliedMigration, Migration, MigrationStatus};
+#[cfg(feature = "metrics")]
+pub use metrics::MetricsRecorder;
+
 #[derive(Error, Debug)]
 pub enum ORMError {
     #[error("std::io::Error")]
@@ -47,12 +89,27 @@ pub enum ORMError {
     InsertError,
     #[error("No connection")]
     NoConnection,
+    #[error("Invalid value: {0}")]
+    InvalidValue(String),
+    #[error("Entity was modified or removed since it was read")]
+    StaleEntity,
+    #[error("Aborted: {0}")]
+    Aborted(String),
+    #[error("Validation failed: {0:?}")]
+    Validation(Vec<FieldError>),
+    #[error("Query timed out")]
+    Timeout,
+    #[error("Table {0} doesn't match its entity: {1:?}")]
+    SchemaMismatch(String, Vec<schema::Mismatch>),
 }
 
 pub trait TableSerialize {
     fn name(&self) -> String{
         "Test".to_string()
     }
+    fn schema(&self) -> Option<String> {
+        None
+    }
     fn get_id(&self) -> String {
         "0".to_string()
     }
@@ -61,17 +118,104 @@ pub trait TableDeserialize {
     fn same_name() -> String{
         "Test".to_string()
     }
+    fn same_schema() -> Option<String> {
+        None
+    }
     fn fields() -> Vec<String>{
         Vec::new()
     }
+    // the column which marks the row as removed, see `ORMTrait::remove`
+    fn soft_delete() -> Option<String> {
+        None
+    }
+    fn columns() -> Vec<Column> {
+        Self::fields().into_iter().map(|name| Column::new(name.as_str())).collect()
+    }
+    // the `#[validate(...)]` rules of the fields, see `validation`
+    fn validate(&self) -> Result<(), ORMError> {
+        Ok(())
+    }
+    // the hooks of entities deriving with `#[table(hooks)]`
+    fn hooks(&mut self) -> Option<&mut dyn EntityHooks> {
+        None
+    }
+}
+
+// Runs around the persistence of an entity. An error aborts the operation,
+// e.g. `ORMError::Aborted`; the `before_*` hooks run before any SQL and may
+// change the entity which is then written.
+#[async_trait]
+pub trait EntityHooks: Send {
+    async fn before_insert(&mut self) -> Result<(), ORMError> {
+        Ok(())
+    }
+    // On the entity as read back after the insert.
+    async fn after_insert(&mut self) -> Result<(), ORMError> {
+        Ok(())
+    }
+    async fn before_update(&mut self) -> Result<(), ORMError> {
+        Ok(())
+    }
+    async fn after_update(&mut self) -> Result<(), ORMError> {
+        Ok(())
+    }
+    async fn before_delete(&mut self) -> Result<(), ORMError> {
+        Ok(())
+    }
+    async fn after_delete(&mut self) -> Result<(), ORMError> {
+        Ok(())
+    }
+    async fn after_load(&mut self) -> Result<(), ORMError> {
+        Ok(())
+    }
 }
 
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Column {
+    pub name: String,
+    // unit enum variants are stored as their index instead of their name
+    pub as_integer: bool,
+    // the value is always stored as JSON text
+    pub json: bool,
+    // UUIDs are stored as 16 raw bytes instead of text
+    pub binary: bool,
+    // the value is converted by the module of `#[column(with = "module")]`
+    pub codec: Option<sql_value::Codec>,
+    // the version of the row for optimistic locking, incremented on update
+    pub version: bool,
+    // set to the time of the clock on insert
+    pub created_at: bool,
+    // set to the time of the clock on insert and update
+    pub updated_at: bool,
+    // the Rust type of the field, e.g. `Option<chrono::DateTime<Utc>>`
+    pub rust_type: String,
+    // filled in by the database on insert, as the `id` is unless the field
+    // says `#[column(generated = false)]`, e.g. for a UUID key
+    pub generated: bool,
+}
 
+impl Column {
+    pub fn new(name: &str) -> Self {
+        Column {
+            name: name.to_string(),
+            generated: name == "id",
+            ..Default::default()
+        }
+    }
+}
 
+
+
+// Values are kept as text, binary values as their bytes in lowercase hex.
 #[derive(Debug, Clone)]
 pub struct Row {
     pub columns: HashMap<i32,Option<String>>,
 }
+#[cfg(any(feature = "sqlite", feature = "mysql"))]
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
 impl Row {
     pub fn new() -> Self {
         let columns = HashMap::new();
@@ -134,15 +278,41 @@ pub trait ORMTrait<O:ORMTrait<O>> {
         where T: for<'a> Deserialize<'a> + TableDeserialize + Debug + 'static;
     fn find_all<T>(&self) -> QueryBuilder<Vec<T>, T, O>
         where T: for<'a> Deserialize<'a> + TableDeserialize + Debug + 'static;
-    fn modify<T>(&self, data: T) -> QueryBuilder<usize, (), O>
+    fn modify<T>(&self, data: T) -> QueryBuilder<usize, T, O>
+        where T: TableDeserialize + TableSerialize + Serialize + 'static;
+    fn remove<T>(&self, data: T) -> QueryBuilder<usize, T, O>
         where T: TableDeserialize + TableSerialize + Serialize + 'static;
-    fn remove<T>(&self, data: T) -> QueryBuilder<usize, (), O>
+    fn force_remove<T>(&self, data: T) -> QueryBuilder<'_, usize, T, O>
+        where T: TableDeserialize + TableSerialize + Serialize + 'static;
+    fn restore<T>(&self, data: T) -> QueryBuilder<'_, usize, T, O>
         where T: TableDeserialize + TableSerialize + Serialize + 'static;
     fn query<T>(&self, query: &str) -> QueryBuilder<Vec<T>, T, O>;
+    fn count<T: TableDeserialize>(&self) -> Aggregate<'_, i64, T, O>;
+    fn exists<T: TableDeserialize>(&self) -> Aggregate<'_, bool, T, O>;
+    fn sum<T: TableDeserialize, V: FromStr>(&self, column: &str) -> Aggregate<'_, Option<V>, T, O>;
+    fn avg<T: TableDeserialize, V: FromStr>(&self, column: &str) -> Aggregate<'_, Option<V>, T, O>;
+    fn min<T: TableDeserialize, V: FromStr>(&self, column: &str) -> Aggregate<'_, Option<V>, T, O>;
+    fn max<T: TableDeserialize, V: FromStr>(&self, column: &str) -> Aggregate<'_, Option<V>, T, O>;
+    fn group_by<T: TableDeserialize, K: FromStr>(&self, column: &str) -> GroupBy<'_, K, T, O>;
     fn query_update(&self, query: &str) -> QueryBuilder<usize, (), O>;
     fn protect(&self, value: &str) -> String;
     fn escape(str: &str) -> String;
     fn escape_json(input: &str) -> String;
+    fn quote(identifier: &str) -> String;
+    fn json_path(column: &str, path: &str) -> String;
+    // The condition which keeps soft-deleted rows of `T` out of queries.
+    fn not_deleted<T: TableDeserialize>() -> Option<String> {
+        T::soft_delete().map(|column| format!("{} is null", Self::quote(column.as_str())))
+    }
+    fn deleted<T: TableDeserialize>() -> Option<String> {
+        T::soft_delete().map(|column| format!("{} is not null", Self::quote(column.as_str())))
+    }
+    fn quote_table(schema: Option<String>, name: &str) -> String {
+        match schema {
+            Some(schema) => format!("{}.{}", Self::quote(schema.as_str()), Self::quote(name)),
+            None => Self::quote(name),
+        }
+    }
     async fn init(&self, script: &str) -> Result<(), ORMError>;
     async fn change(&self, update_query: &str) -> Result<(), ORMError>;
 }
@@ -153,6 +323,70 @@ pub struct QueryBuilder<'a, R, E, O: ORMTrait<O>> {
     entity:  std::marker::PhantomData<E>,
     orm: &'a O,
     result: std::marker::PhantomData<std::marker::PhantomData<R>>,
+    // the entity of an `add`, `modify` or `remove`, for its hooks
+    data: futures::lock::Mutex<Option<Operation<E>>>,
+    // overrides the default timeout of the ORM
+    timeout: Option<std::time::Duration>,
+    // the parts of a `find_*` query, which is then put together when it runs
+    // instead of running `query`
+    select: Option<Select>,
+    // the values of the `?` placeholders of the query
+    params: Vec<SqlValue>,
+}
+
+impl<R, E, O: ORMTrait<O>> QueryBuilder<'_, R, E, O> {
+    // Fails the query with `ORMError::Timeout` when it runs longer.
+    pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl<R, E, O: ORMTrait<O>> QueryBuilder<'_, R, E, O> {
+    fn sql(&self) -> String {
+        match &self.select {
+            Some(select) => select.sql(),
+            None => self.query.clone(),
+        }
+    }
+}
+
+enum Operation<E> {
+    Insert(E),
+    Update(E),
+    // forced past the soft delete column
+    Delete(E, bool),
+    Restore(E),
+}
+
+// A `find_*` query: `with_deleted` and `only_deleted` swap its soft delete
+// condition, and `limit` goes after the conditions.
+#[derive(Clone)]
+struct Select {
+    table: String,
+    filter: Option<String>,
+    // `None` when the soft-deleted rows are taken too
+    deleted: Option<String>,
+    limit: Option<i32>,
+}
+
+impl Select {
+    fn new(table: String, filter: Option<String>, deleted: Option<String>) -> Self {
+        Select { table, filter, deleted, limit: None }
+    }
+
+    fn sql(&self) -> String {
+        let mut sql = format!("select * from {}", self.table);
+        match (&self.filter, &self.deleted) {
+            (Some(filter), Some(deleted)) => sql = format!("{sql} where ({filter}) and {deleted}"),
+            (Some(condition), None) | (None, Some(condition)) => sql = format!("{sql} where {condition}"),
+            (None, None) => {}
+        }
+        if let Some(limit) = self.limit {
+            sql = format!("{sql} limit {limit}");
+        }
+        sql
+    }
 }
 
 
@@ -160,10 +394,19 @@ pub struct QueryBuilder<'a, R, E, O: ORMTrait<O>> {
 
 #[cfg(test)]
 mod tests {
-    use crate::ORMError;
+    use crate::{ORMError, Select};
 
     #[tokio::test]
     async fn test() -> Result<(), ORMError> {
         Ok(())
     }
+
+    #[test]
+    fn test_select() {
+        let select = Select::new("note".to_string(), Some("a is null or b".to_string()), Some("d is null".to_string()));
+        assert_eq!("select * from note where (a is null or b) and d is null", select.sql());
+        let select = Select { deleted: None, limit: Some(5), ..select };
+        assert_eq!("select * from note where a is null or b limit 5", select.sql());
+        assert_eq!("select * from note where d is not null", Select::new("note".to_string(), None, Some("d is not null".to_string())).sql());
+    }
 }
\ No newline at end of file
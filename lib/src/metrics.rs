@@ -0,0 +1,54 @@
+// Metrics of the statements which an ORM executes: a `MetricsSink` set with
+// `set_metrics_sink` is told of every statement and of the time each waited
+// for the connection.
+//
+//     conn.set_metrics_sink(Some(Arc::new(MetricsRecorder)));
+//
+// With the `metrics` feature, `MetricsRecorder` hands them to the recorder of
+// the `metrics` crate.
+
+use std::fmt::Debug;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryEvent {
+    // `sqlite` or `mysql`
+    pub backend: &'static str,
+    // the first word of the statement, e.g. `select`
+    pub operation: String,
+    // empty when the statement names none
+    pub table: String,
+    pub latency: Duration,
+    pub success: bool,
+}
+
+pub trait MetricsSink: Debug + Send + Sync {
+    fn query(&self, event: &QueryEvent);
+
+    fn connection_wait(&self, _backend: &'static str, _wait: Duration) {}
+}
+
+// Counts the statements in `ormlib_queries_total` and records their latency in
+// `ormlib_query_duration_seconds`, by backend, operation, table and success,
+// and the waits in `ormlib_connection_wait_seconds`, by backend.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsRecorder;
+
+#[cfg(feature = "metrics")]
+impl MetricsSink for MetricsRecorder {
+    fn query(&self, event: &QueryEvent) {
+        let labels = [
+            ("backend", event.backend.to_string()),
+            ("operation", event.operation.clone()),
+            ("table", event.table.clone()),
+            ("success", event.success.to_string()),
+        ];
+        ::metrics::counter!("ormlib_queries_total", &labels).increment(1);
+        ::metrics::histogram!("ormlib_query_duration_seconds", &labels).record(event.latency.as_secs_f64());
+    }
+
+    fn connection_wait(&self, backend: &'static str, wait: Duration) {
+        ::metrics::histogram!("ormlib_connection_wait_seconds", "backend" => backend).record(wait.as_secs_f64());
+    }
+}
@@ -0,0 +1,392 @@
+// Migrations from SQL files, as the `ormlib` binary runs them: a directory of
+// `<version>_<name>.up.sql` files with their `<version>_<name>.down.sql`,
+// applied in the order of their versions, which are UTC timestamps
+// `YYYYMMDDHHMMSS`.
+//
+//     let migrations = ormlib::migration::load("migrations")?;
+//     let applied = conn.applied_migrations().await?;
+//     for migration in ormlib::migration::pending(&migrations, &applied) {
+//         conn.apply_migration(migration).await?;
+//     }
+//
+// The applied ones are kept in the `ormlib_migration` table with the checksum
+// of their up script, so that a file changed after it was applied shows up as
+// modified. `ORMTrait::change` records its statements in the same table, as
+// the migrations `change_000001`, `change_000002`, ... by the order of the
+// calls; those have no file and are neither missing nor reverted. The count of
+// an older `ormlib_last_change` table is taken over by the first call.
+//
+// SQLite runs a migration and its record in one transaction. MySQL commits at
+// each DDL statement, so there the record is written first and marked dirty
+// until the last statement is done: a migration failing halfway stays dirty,
+// and no other migration runs until the database is repaired by hand and its
+// row is fixed or deleted.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::{ORMError, Row};
+
+pub const TABLE: &str = "ormlib_migration";
+// the version prefix of the migrations of `ORMTrait::change`
+pub const CHANGE: &str = "change_";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    pub version: String,
+    pub name: String,
+    pub up: String,
+    // empty when there is no down file
+    pub down: String,
+}
+
+impl Migration {
+    // FNV-1a of the up script in hex, the same on every platform and release.
+    pub fn checksum(&self) -> String {
+        let hash = self.up.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        format!("{:016x}", hash)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedMigration {
+    pub version: String,
+    pub name: String,
+    pub checksum: String,
+    // UTC `YYYY-MM-DD HH:MM:SS` from the clock of the connection
+    pub applied_at: String,
+    // still running, or failed halfway on MySQL
+    pub dirty: bool,
+}
+
+impl AppliedMigration {
+    // Whether it was recorded by `ORMTrait::change` rather than from a file.
+    pub fn from_code(&self) -> bool {
+        self.version.starts_with(CHANGE)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationStatus {
+    Applied,
+    Pending,
+    // applied, but its up file has changed since
+    Modified,
+    // applied, but there is no file for it anymore
+    Missing,
+    // failed halfway, see above
+    Dirty,
+}
+
+impl fmt::Display for MigrationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            MigrationStatus::Applied => "applied",
+            MigrationStatus::Pending => "pending",
+            MigrationStatus::Modified => "modified",
+            MigrationStatus::Missing => "missing",
+            MigrationStatus::Dirty => "dirty",
+        };
+        f.pad(status)
+    }
+}
+
+// The migrations of a directory in the order of their versions. Other files
+// are ignored.
+pub fn load(dir: impl AsRef<Path>) -> Result<Vec<Migration>, ORMError> {
+    let mut migrations: Vec<Migration> = Vec::new();
+    for entry in std::fs::read_dir(dir.as_ref())? {
+        let path = entry?.path();
+        let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let Some((version, name)) = file_name.strip_suffix(".up.sql").and_then(|stem| stem.split_once('_')) else {
+            continue;
+        };
+        if version.is_empty() || !version.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        if migrations.iter().any(|migration| migration.version == version) {
+            return Err(ORMError::InvalidValue(format!("two migrations of version {}", version)));
+        }
+        let down = path.with_file_name(format!("{}_{}.down.sql", version, name));
+        migrations.push(Migration {
+            version: version.to_string(),
+            name: name.to_string(),
+            up: std::fs::read_to_string(&path)?,
+            down: match down.exists() {
+                true => std::fs::read_to_string(down)?,
+                false => String::new(),
+            },
+        });
+    }
+    migrations.sort_by(|a, b| a.version.cmp(&b.version));
+    Ok(migrations)
+}
+
+// Writes the empty up and down files of a new migration, versioned by `now`.
+pub fn create(dir: impl AsRef<Path>, name: &str, now: SystemTime) -> Result<(PathBuf, PathBuf), ORMError> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(ORMError::InvalidValue(format!("migration name {:?} isn't made of letters, digits and _", name)));
+    }
+    let version: String = crate::clock::timestamp(now, false).chars().take(19).filter(char::is_ascii_digit).collect();
+    std::fs::create_dir_all(dir.as_ref())?;
+    let up = dir.as_ref().join(format!("{}_{}.up.sql", version, name));
+    let down = dir.as_ref().join(format!("{}_{}.down.sql", version, name));
+    if up.exists() || down.exists() {
+        return Err(ORMError::InvalidValue(format!("migration {}_{} exists", version, name)));
+    }
+    std::fs::write(&up, format!("-- {}: up\n", name))?;
+    std::fs::write(&down, format!("-- {}: down\n", name))?;
+    Ok((up, down))
+}
+
+// The migrations which aren't applied yet, in order.
+pub fn pending<'a>(migrations: &'a [Migration], applied: &[AppliedMigration]) -> Vec<&'a Migration> {
+    migrations.iter()
+        .filter(|migration| !applied.iter().any(|applied| applied.version == migration.version))
+        .collect()
+}
+
+// The status of every migration, of a file or applied, by version.
+pub fn status(migrations: &[Migration], applied: &[AppliedMigration]) -> Vec<(String, String, MigrationStatus)> {
+    let mut status: Vec<(String, String, MigrationStatus)> = migrations.iter().map(|migration| {
+        let status = match applied.iter().find(|applied| applied.version == migration.version) {
+            None => MigrationStatus::Pending,
+            Some(applied) if applied.dirty => MigrationStatus::Dirty,
+            Some(applied) if applied.checksum != migration.checksum() => MigrationStatus::Modified,
+            Some(_) => MigrationStatus::Applied,
+        };
+        (migration.version.clone(), migration.name.clone(), status)
+    }).collect();
+    for applied in applied.iter().filter(|applied| !migrations.iter().any(|migration| migration.version == applied.version)) {
+        let missing = match (applied.dirty, applied.from_code()) {
+            (true, _) => MigrationStatus::Dirty,
+            (false, true) => MigrationStatus::Applied,
+            (false, false) => MigrationStatus::Missing,
+        };
+        status.push((applied.version.clone(), applied.name.clone(), missing));
+    }
+    status.sort_by(|a, b| a.0.cmp(&b.0));
+    status
+}
+
+// The statements of a script, split at the semicolons outside of quotes,
+// comments and the BEGIN ... END bodies of triggers and routines. Statements
+// of only comments are left out. A backslash escapes the next character of a
+// quoted string, as in MySQL.
+pub(crate) fn statements(script: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut statement = String::new();
+    let mut code = false;
+    // The open BEGIN and CASE blocks, closed by their END.
+    let mut depth = 0usize;
+    let mut chars = script.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' | '`' => {
+                statement.push(c);
+                code = true;
+                while let Some(next) = chars.next() {
+                    statement.push(next);
+                    if next == '\\' && c != '`' {
+                        statement.extend(chars.next());
+                    } else if next == c {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                statement.push(c);
+                for next in chars.by_ref() {
+                    statement.push(next);
+                    if next == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                statement.push(c);
+                statement.extend(chars.next());
+                let mut last = ' ';
+                for next in chars.by_ref() {
+                    statement.push(next);
+                    if last == '*' && next == '/' {
+                        break;
+                    }
+                    last = next;
+                }
+            }
+            ';' if depth == 0 => {
+                if code {
+                    statements.push(statement.trim().to_string());
+                }
+                statement.clear();
+                code = false;
+            }
+            c if is_word(c) => {
+                let mut word = c.to_string();
+                while let Some(&next) = chars.peek().filter(|next| is_word(**next)) {
+                    word.push(next);
+                    chars.next();
+                }
+                statement.push_str(&word);
+                match word.to_ascii_uppercase().as_str() {
+                    // A BEGIN that starts a statement starts a transaction.
+                    "BEGIN" if code => depth += 1,
+                    "CASE" => depth += 1,
+                    "END" => {
+                        // END IF, END LOOP and the like close blocks that
+                        // were not counted.
+                        let after: String = chars.clone().skip_while(|c| c.is_whitespace())
+                            .take_while(|c| is_word(*c)).collect();
+                        if !["IF", "LOOP", "WHILE", "REPEAT"].contains(&after.to_ascii_uppercase().as_str()) {
+                            depth = depth.saturating_sub(1);
+                        }
+                    }
+                    _ => {}
+                }
+                code = true;
+            }
+            _ => {
+                statement.push(c);
+                code |= !c.is_whitespace();
+            }
+        }
+    }
+    if code {
+        statements.push(statement.trim().to_string());
+    }
+    statements
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+// The migration of the `count`th `ORMTrait::change` call of a connection.
+pub(crate) fn change(count: u32, query: &str) -> Migration {
+    Migration {
+        version: format!("{}{:06}", CHANGE, count),
+        name: "change".to_string(),
+        up: query.to_string(),
+        down: String::new(),
+    }
+}
+
+// The applied migrations of the rows of version, name, checksum, time and
+// dirty flag.
+pub(crate) fn applied(rows: Vec<Row>) -> Vec<AppliedMigration> {
+    rows.iter().map(|row| AppliedMigration {
+        version: row.get(0).unwrap_or_default(),
+        name: row.get(1).unwrap_or_default(),
+        checksum: row.get(2).unwrap_or_default(),
+        applied_at: row.get(3).unwrap_or_default(),
+        dirty: row.get::<i64>(4).unwrap_or_default() != 0,
+    }).collect()
+}
+
+// Fails when a migration is dirty, before any other one runs.
+pub(crate) fn clean(applied: &[AppliedMigration]) -> Result<(), ORMError> {
+    match applied.iter().find(|applied| applied.dirty) {
+        Some(applied) => Err(ORMError::InvalidValue(format!("migration {}_{} is dirty: repair the database, then fix or delete its row in {}",
+            applied.version, applied.name, TABLE))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statements() {
+        let script = "-- users\nCREATE TABLE user (id INTEGER, name TEXT DEFAULT ';');\n\
+            /* a ; comment */ INSERT INTO user (name) VALUES (\"a;b\");\n-- done;\n";
+        assert_eq!(vec![
+            "-- users\nCREATE TABLE user (id INTEGER, name TEXT DEFAULT ';')",
+            "/* a ; comment */ INSERT INTO user (name) VALUES (\"a;b\")",
+        ], statements(script));
+        assert!(statements("-- nothing\n").is_empty());
+        assert_eq!(vec!["SELECT 1 /*/ x */"], statements("SELECT 1 /*/ x */"));
+    }
+
+    #[test]
+    fn test_statements_blocks() {
+        let script = "CREATE TRIGGER touch AFTER UPDATE ON user FOR EACH ROW BEGIN\n\
+            UPDATE user SET name = CASE WHEN new.name = '' THEN NULL ELSE new.name END WHERE id = new.id;\n\
+            INSERT INTO log (begin_at) VALUES (1);\nEND;\nBEGIN;\nDELETE FROM log;\nCOMMIT;";
+        assert_eq!(vec![
+            "CREATE TRIGGER touch AFTER UPDATE ON user FOR EACH ROW BEGIN\n\
+            UPDATE user SET name = CASE WHEN new.name = '' THEN NULL ELSE new.name END WHERE id = new.id;\n\
+            INSERT INTO log (begin_at) VALUES (1);\nEND",
+            "BEGIN",
+            "DELETE FROM log",
+            "COMMIT",
+        ], statements(script));
+        let script = "CREATE PROCEDURE clear() BEGIN IF 1 THEN DELETE FROM log; END IF; END; SELECT 1";
+        assert_eq!(vec!["CREATE PROCEDURE clear() BEGIN IF 1 THEN DELETE FROM log; END IF; END", "SELECT 1"],
+            statements(script));
+    }
+
+    #[test]
+    fn test_statements_escapes() {
+        assert_eq!(vec!["INSERT INTO user (name) VALUES ('it\\'s;')", "SELECT \"a\\\";\""],
+            statements("INSERT INTO user (name) VALUES ('it\\'s;'); SELECT \"a\\\";\";"));
+        assert_eq!(vec!["SELECT '\\\\'", "SELECT 1"], statements("SELECT '\\\\'; SELECT 1"));
+    }
+
+    #[test]
+    fn test_status() {
+        let migration = |version: &str, up: &str| Migration {
+            version: version.to_string(),
+            name: "m".to_string(),
+            up: up.to_string(),
+            down: String::new(),
+        };
+        let migrations = vec![migration("1", "a"), migration("2", "b"), migration("4", "d")];
+        let applied = |m: &Migration, checksum: String| AppliedMigration {
+            version: m.version.clone(),
+            name: m.name.clone(),
+            checksum,
+            applied_at: String::new(),
+            dirty: false,
+        };
+        let mut applied = vec![
+            applied(&migrations[0], migrations[0].checksum()),
+            applied(&migrations[1], migration("2", "changed").checksum()),
+            applied(&migration("3", "c"), String::new()),
+            applied(&change(1, "e"), String::new()),
+        ];
+        assert_eq!(vec![MigrationStatus::Applied, MigrationStatus::Modified, MigrationStatus::Missing, MigrationStatus::Pending, MigrationStatus::Applied],
+            status(&migrations, &applied).into_iter().map(|(_, _, status)| status).collect::<Vec<_>>());
+        assert_eq!(vec![&migrations[2]], pending(&migrations, &applied));
+        assert!(clean(&applied).is_ok());
+        applied[0].dirty = true;
+        assert_eq!(MigrationStatus::Dirty, status(&migrations, &applied)[0].2);
+        assert!(clean(&applied).is_err());
+        assert_eq!("af63dc4c8601ec8c", migration("1", "a").checksum());
+        assert_eq!("change_000001", applied[3].version);
+        assert!(applied[3].from_code() && !applied[0].from_code());
+    }
+
+    #[test]
+    fn test_files() -> Result<(), ORMError> {
+        let dir = std::env::temp_dir().join(format!("ormlib_migrations_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let now = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let (up, _) = create(&dir, "create_user", now)?;
+        assert_eq!("20231114221320_create_user.up.sql", up.file_name().unwrap().to_str().unwrap());
+        assert!(create(&dir, "create_user", now).is_err());
+        assert!(create(&dir, "bad name", now).is_err());
+        std::fs::write(dir.join("20231114221321_seed.up.sql"), "INSERT INTO user DEFAULT VALUES;")?;
+        std::fs::write(dir.join("notes.txt"), "")?;
+        let migrations = load(&dir)?;
+        assert_eq!(vec!["create_user", "seed"], migrations.iter().map(|m| m.name.as_str()).collect::<Vec<_>>());
+        assert_eq!("-- create_user: down\n", migrations[0].down);
+        assert!(migrations[1].down.is_empty());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -1,16 +1,150 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime};
 use async_trait::async_trait;
+use futures::future::BoxFuture;
 use futures::lock::Mutex;
 use mysql_async::Conn;
 use mysql_async::prelude::*;
+use mysql_async::consts::ColumnType;
+use mysql_async::Value;
 
 use serde::{Deserialize, Serialize};
-use crate::{deserializer_key_values, ORMError, ORMTrait, QueryBuilder, Row, serializer_error, serializer_key_values, serializer_types, serializer_values, TableDeserialize, TableSerialize};
+use crate::cache::{CacheConfig, CacheStats, QueryCache};
+use crate::instrument::QueryTimer;
+use crate::introspect::{self, ColumnInfo, ForeignKeyInfo, IndexInfo};
+use crate::migration::{self, AppliedMigration, Migration};
+use crate::metrics::MetricsSink;
+use crate::retry::{retry, RetryPolicy};
+use crate::schema::{self, Dialect, Mismatch};
+use crate::session::Order;
+use crate::unit;
+use crate::{aggregate, Aggregate, Clock, Cursor, deserializer_key_values, GroupBy, Instrumentation, Page, Paginate, Session, Tracked, Operation, ORMError, ORMTrait, QueryBuilder, Row, Select, SqlValue, serializer_error, serializer_key_values, serializer_types, serializer_values, TableDeserialize, TableSerialize, SystemClock, to_hex};
+
+// Character set of binary strings.
+const BINARY_CHARSET: u16 = 63;
+
+// The text of a value of the binary protocol, as the text protocol has it.
+// Decimals arrive as their digits, so no digit is lost.
+fn value_text(value: &Value, column_type: ColumnType, binary: bool) -> Option<String> {
+    let text = match value {
+        Value::NULL => return None,
+        Value::Bytes(bytes) if binary => to_hex(bytes),
+        Value::Bytes(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        Value::Int(v) => v.to_string(),
+        Value::UInt(v) => v.to_string(),
+        Value::Float(v) => v.to_string(),
+        Value::Double(v) => v.to_string(),
+        Value::Date(year, month, day, ..) if column_type == ColumnType::MYSQL_TYPE_DATE => {
+            format!("{:04}-{:02}-{:02}", year, month, day)
+        }
+        Value::Date(year, month, day, hour, minute, second, micros) => {
+            let text = format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second);
+            match micros {
+                0 => text,
+                micros => format!("{}.{:06}", text, micros),
+            }
+        }
+        Value::Time(negative, days, hours, minutes, seconds, micros) => {
+            let sign = if *negative { "-" } else { "" };
+            let text = format!("{}{:02}:{:02}:{:02}", sign, days * 24 + *hours as u32, minutes, seconds);
+            match micros {
+                0 => text,
+                micros => format!("{}.{:06}", text, micros),
+            }
+        }
+    };
+    Some(text)
+}
+
+// The values of the placeholders of a statement.
+fn params(values: &[SqlValue]) -> mysql_async::Params {
+    if values.is_empty() {
+        return mysql_async::Params::Empty;
+    }
+    mysql_async::Params::Positional(values.iter().map(|value| match value {
+        SqlValue::Null => Value::NULL,
+        SqlValue::Integer(int) => Value::Int(*int),
+        SqlValue::Real(real) => Value::Double(*real),
+        SqlValue::Text(text) => Value::Bytes(text.as_bytes().to_vec()),
+        SqlValue::Blob(bytes) => Value::Bytes(bytes.clone()),
+    }).collect())
+}
 
 #[derive(Debug)]
 pub struct ORM {
     conn: Mutex<Option<Conn>>,
+    change_count: Mutex<u32>,
+    clock: std::sync::Mutex<Arc<dyn Clock>>,
+    cache: std::sync::Mutex<Option<QueryCache>>,
+    instrumentation: std::sync::Mutex<Instrumentation>,
+    retry: std::sync::Mutex<RetryPolicy>,
+    metrics: std::sync::Mutex<Option<Arc<dyn MetricsSink>>>,
+    default_timeout: std::sync::Mutex<Option<Duration>>,
+    // held by the open transaction or flush, see `unit`
+    unit: Mutex<()>,
+    // for the side connections which kill queries
+    pool: mysql_async::Pool,
+    // the kill of the query of a dropped future, see `KillQuery`
+    kill: std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+// The error of a query which `KILL QUERY` interrupted.
+const ER_QUERY_INTERRUPTED: u16 = 1317;
+
+// Kills the query of the connection `id` from a side connection.
+async fn kill_query(pool: mysql_async::Pool, id: u32) {
+    let killed = async {
+        pool.get_conn().await?.query_drop(format!("KILL QUERY {}", id)).await
+    }.await;
+    if let Err(e) = killed {
+        log::error!("{:?}", e);
+    }
+}
+
+// Kills the query of a connection when the future of the query is dropped
+// before the query is done, unless disarmed. The kill runs in the background,
+// and the next statement waits for it so that it can't be killed instead.
+struct KillQuery<'a> {
+    orm: &'a ORM,
+    id: u32,
+    armed: bool,
+}
+
+impl Drop for KillQuery<'_> {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let kill = runtime.spawn(kill_query(self.orm.pool.clone(), self.id));
+        *self.orm.kill.lock().unwrap() = Some(kill);
+    }
+}
+
+// Takes the `utc` parameter out of the URL, as mysql_async rejects the
+// parameters it doesn't know.
+fn utc_param(url: &str) -> (String, bool) {
+    let Some((base, query)) = url.split_once('?') else {
+        return (url.to_string(), false);
+    };
+    let mut utc = false;
+    let params: Vec<&str> = query.split('&').filter(|param| match param.split_once('=') {
+        Some(("utc", value)) => {
+            utc = value == "true";
+            false
+        }
+        _ => true,
+    }).collect();
+    match params.is_empty() {
+        true => (base.to_string(), utc),
+        false => (format!("{}?{}", base, params.join("&")), utc),
+    }
 }
 
 impl ORM {
@@ -18,12 +152,472 @@ impl ORM {
     pub async fn connect(url: String) -> Result<Arc<ORM>, ORMError>
         where Arc<ORM>: Send + Sync + 'static
     {
-        let pool = mysql_async::Pool::new(url.as_str());
+        // The statements which the ORM builds are prepared, and kept in the
+        // statement cache of the connection: its capacity is the
+        // `stmt_cache_size` parameter of the URL, 32 by default.
+        let (url, utc) = utc_param(url.as_str());
+        let mut opts = mysql_async::OptsBuilder::from_opts(mysql_async::Opts::from_url(url.as_str()).map_err(mysql_async::Error::from)?);
+        // With `utc=true` the sessions are in UTC, which TIMESTAMP columns
+        // convert date-times from and to, as every connection of the pool is
+        // set up again after a reset.
+        if utc {
+            opts = opts.setup(vec!["SET time_zone = '+00:00'"]);
+        }
+        let pool = mysql_async::Pool::new(opts);
         let conn = pool.get_conn().await?;
         Ok(Arc::new(ORM {
             conn: Mutex::new(Some(conn)),
+            change_count: 0.into(),
+            pool,
+            clock: std::sync::Mutex::new(Arc::new(SystemClock)),
+            cache: std::sync::Mutex::new(None),
+            instrumentation: std::sync::Mutex::new(Instrumentation::default()),
+            retry: std::sync::Mutex::new(RetryPolicy::none()),
+            metrics: std::sync::Mutex::new(None),
+            default_timeout: std::sync::Mutex::new(None),
+            unit: Mutex::new(()),
+            kill: std::sync::Mutex::new(None),
         }))
     }
+
+    // Replaces the clock of the timestamp columns, e.g. by a `FixedClock` in tests.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.lock().unwrap() = clock;
+    }
+
+    fn now(&self) -> SystemTime {
+        self.clock.lock().unwrap().now()
+    }
+
+    // Caches the rows of the `find_*` queries, see `cache`.
+    pub fn enable_cache(&self, config: CacheConfig) {
+        *self.cache.lock().unwrap() = Some(QueryCache::new(config));
+    }
+
+    pub fn disable_cache(&self) {
+        *self.cache.lock().unwrap() = None;
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().as_ref().map(QueryCache::stats).unwrap_or_default()
+    }
+
+    // The slow query threshold and redaction of the query logs, see `instrument`.
+    pub fn set_instrumentation(&self, instrumentation: Instrumentation) {
+        *self.instrumentation.lock().unwrap() = instrumentation;
+    }
+
+    // The timeout of the queries without one of their own, none by default.
+    pub fn set_default_timeout(&self, timeout: Option<Duration>) {
+        *self.default_timeout.lock().unwrap() = timeout;
+    }
+
+    fn timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        timeout.or(*self.default_timeout.lock().unwrap())
+    }
+
+    // Runs `query` on the connection `id`, which the caller holds. The query
+    // is killed once the timeout is over, or when the future is dropped before
+    // it is done. On a timeout the kill and then the query are awaited, which
+    // leaves the connection ready for the next query; the query fails with
+    // `ORMError::Timeout` when the kill interrupted it.
+    async fn deadline<R>(&self, id: u32, timeout: Option<Duration>, query: impl Future<Output = Result<R, ORMError>>) -> Result<R, ORMError> {
+        let mut kill = KillQuery { orm: self, id, armed: true };
+        tokio::pin!(query);
+        let r = match timeout {
+            Some(timeout) => tokio::select! {
+                r = &mut query => r,
+                _ = tokio::time::sleep(timeout) => {
+                    kill.armed = false;
+                    kill_query(self.pool.clone(), id).await;
+                    match (&mut query).await {
+                        Err(ORMError::MySQLError(mysql_async::Error::Server(e))) if e.code == ER_QUERY_INTERRUPTED => Err(ORMError::Timeout),
+                        r => r,
+                    }
+                }
+            },
+            None => query.await,
+        };
+        kill.armed = false;
+        r
+    }
+
+    // Retries on transient errors, see `retry`.
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        *self.retry.lock().unwrap() = policy;
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        *self.retry.lock().unwrap()
+    }
+
+    // Statements within a transaction aren't retried on their own, the whole
+    // transaction is.
+    fn statement_retry_policy(&self) -> RetryPolicy {
+        match unit::depth(self) {
+            0 => self.retry_policy(),
+            _ => RetryPolicy::none(),
+        }
+    }
+
+    // Runs `f` in a transaction, which is committed when `f` succeeds and
+    // rolled back when it fails. On a transient error the transaction is rolled
+    // back and runs again as the retry policy allows, so `f` can be called more
+    // than once; the connection is held until the last attempt is over. Within
+    // another transaction it is a savepoint, retried with that transaction.
+    //
+    //     conn.transaction(|orm| Box::pin(async move {
+    //         orm.add(User { id: 0, name: "John".to_string() }).apply().await?;
+    //         Ok(())
+    //     })).await?;
+    pub async fn transaction<R, F>(&self, f: F) -> Result<R, ORMError>
+        where F: for<'c> Fn(&'c ORM) -> BoxFuture<'c, Result<R, ORMError>>
+    {
+        let depth = unit::depth(self);
+        let (_unit, policy) = match depth {
+            0 => (Some(self.unit.lock().await), self.retry_policy()),
+            _ => (None, RetryPolicy::none()),
+        };
+        retry(policy, ORMError::is_transient, || self.attempt(depth, f(self))).await
+    }
+
+    // Runs `work` in a transaction which holds the connection until it is
+    // over, or in a savepoint within another transaction, see `unit`.
+    async fn atomically<R>(&self, work: impl Future<Output = Result<R, ORMError>>) -> Result<R, ORMError> {
+        let depth = unit::depth(self);
+        let _unit = match depth {
+            0 => Some(self.unit.lock().await),
+            _ => None,
+        };
+        self.attempt(depth, work).await
+    }
+
+    // Runs `work` in the transaction or savepoint at `depth`, whose unit the
+    // caller holds. It is rolled back when `work` or the commit fails.
+    async fn attempt<R>(&self, depth: usize, work: impl Future<Output = Result<R, ORMError>>) -> Result<R, ORMError> {
+        let (begin, commit, rollback) = unit::statements(depth, "start transaction");
+        unit::enter(self, async {
+            self.query_update(begin.as_str()).exec().await?;
+            let r = match work.await {
+                Ok(r) => self.query_update(commit.as_str()).exec().await.map(|_| r),
+                Err(e) => Err(e),
+            };
+            if r.is_err() {
+                for statement in rollback {
+                    // nothing to roll back when the transaction is already over
+                    if let Err(rollback) = self.query_update(statement.as_str()).exec().await {
+                        log::debug!("{:?}", rollback);
+                    }
+                }
+            }
+            r
+        }).await
+    }
+
+    // Told of every statement and of the waits for the connection, see `metrics`.
+    pub fn set_metrics_sink(&self, sink: Option<Arc<dyn MetricsSink>>) {
+        *self.metrics.lock().unwrap() = sink;
+    }
+
+    fn timer(&self, query: &str) -> QueryTimer {
+        let metrics = self.metrics.lock().unwrap().clone();
+        self.instrumentation.lock().unwrap().start(query, "mysql", metrics)
+    }
+
+    async fn connection(&self) -> unit::Held<'_, Option<Conn>> {
+        let start = Instant::now();
+        // waits for the transaction of another task to be over
+        let unit = match unit::depth(self) {
+            0 => Some(self.unit.lock().await),
+            _ => None,
+        };
+        let conn = self.conn.lock().await;
+        // the kill of a dropped query, which would otherwise kill the next one
+        let kill = self.kill.lock().unwrap().take();
+        if let Some(kill) = kill {
+            let _ = kill.await;
+        }
+        let metrics = self.metrics.lock().unwrap().clone();
+        if let Some(metrics) = metrics {
+            metrics.connection_wait("mysql", start.elapsed());
+        }
+        unit::Held::new(unit, conn)
+    }
+
+    // Drops the cached rows of the tables which `statement` writes. Writes
+    // call it before and after they run, so that the rows of a query which
+    // runs in between aren't kept.
+    fn invalidate(&self, statement: &str) {
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            cache.invalidate(statement);
+        }
+    }
+
+    // The rows of a `find_*` query, from the cache when it has them.
+    async fn find_rows(&self, query: &str, params: &[SqlValue], timeout: Option<Duration>) -> Result<Vec<Row>, ORMError> {
+        let cached = self.cache.lock().unwrap().as_mut().map(|cache| (cache.get(query, params), cache.generation()));
+        let generation = match cached {
+            Some((Some(rows), _)) => return Ok(rows),
+            Some((None, generation)) => generation,
+            None => 0,
+        };
+        let rows = QueryBuilder { timeout, params: params.to_vec(), ..self.query(query) }.exec().await?;
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            cache.put(query, params, generation, rows.clone());
+        }
+        Ok(rows)
+    }
+
+    // A unit of work over this connection, see `session`.
+    pub fn session(&self) -> Session<'_, ORM> {
+        Session::new(self)
+    }
+
+    // A text literal for the catalog queries.
+    fn literal(text: &str) -> String {
+        format!("'{}'", text.replace('\\', "\\\\").replace('\'', "''"))
+    }
+
+    // The tables of the database, by name, see `introspect`.
+    pub async fn tables(&self) -> Result<Vec<String>, ORMError> {
+        let query = "select table_name from information_schema.tables where table_schema = database() and table_type = 'BASE TABLE' \
+            order by table_name";
+        let rows = self.query::<Row>(query).exec().await?;
+        Ok(introspect::names(rows))
+    }
+
+    // The columns of a table in their order, none when there is no such table.
+    pub async fn columns(&self, table: &str) -> Result<Vec<ColumnInfo>, ORMError> {
+        self.columns_in(None, table).await
+    }
+
+    // The columns of a table of the database `schema`, or of the current one.
+    async fn columns_in(&self, schema: Option<&str>, table: &str) -> Result<Vec<ColumnInfo>, ORMError> {
+        let schema = schema.map(ORM::literal).unwrap_or_else(|| "database()".to_string());
+        let query = format!("select column_name, column_type, is_nullable, column_default, column_key, extra from information_schema.columns \
+            where table_schema = {} and table_name = {} order by ordinal_position", schema, ORM::literal(table));
+        let rows = self.query::<Row>(query.as_str()).exec().await?;
+        Ok(rows.iter().map(|row| ColumnInfo {
+            name: row.get(0).unwrap_or_default(),
+            declared_type: row.get(1).unwrap_or_default(),
+            nullable: row.get::<String>(2).as_deref() == Some("YES"),
+            default: row.get(3),
+            primary_key: row.get::<String>(4).as_deref() == Some("PRI"),
+            auto_increment: row.get::<String>(5).is_some_and(|extra| extra.to_lowercase().contains("auto_increment")),
+        }).collect())
+    }
+
+    pub async fn indexes(&self, table: &str) -> Result<Vec<IndexInfo>, ORMError> {
+        let query = format!("select index_name, non_unique = 0, column_name from information_schema.statistics \
+            where table_schema = database() and table_name = {} order by index_name, seq_in_index", ORM::literal(table));
+        let rows = self.query::<Row>(query.as_str()).exec().await?;
+        Ok(introspect::indexes(rows))
+    }
+
+    pub async fn foreign_keys(&self, table: &str) -> Result<Vec<ForeignKeyInfo>, ORMError> {
+        let query = format!("select k.constraint_name, k.column_name, k.referenced_table_name, k.referenced_column_name, \
+            r.update_rule, r.delete_rule from information_schema.key_column_usage k \
+            join information_schema.referential_constraints r on r.constraint_schema = k.constraint_schema and r.constraint_name = k.constraint_name \
+            where k.table_schema = database() and k.table_name = {} and k.referenced_table_name is not null \
+            order by k.constraint_name, k.ordinal_position", ORM::literal(table));
+        let rows = self.query::<Row>(query.as_str()).exec().await?;
+        Ok(introspect::foreign_keys(rows))
+    }
+
+    // The applied migrations in the order of their versions, see `migration`.
+    pub async fn applied_migrations(&self) -> Result<Vec<AppliedMigration>, ORMError> {
+        self.query_update(format!("CREATE TABLE IF NOT EXISTS {} (version VARCHAR(32) PRIMARY KEY, name VARCHAR(255) NOT NULL, checksum CHAR(16) NOT NULL, applied_at VARCHAR(32) NOT NULL, dirty TINYINT NOT NULL DEFAULT 0)", migration::TABLE).as_str()).exec().await?;
+        let query = format!("select version, name, checksum, applied_at, dirty from {} order by version", migration::TABLE);
+        let rows = self.query::<Row>(query.as_str()).exec().await?;
+        Ok(migration::applied(rows))
+    }
+
+    // Records the migration as dirty, runs its up script and clears the flag.
+    // MySQL commits at each DDL statement, so a migration failing halfway
+    // stays dirty rather than being rolled back, see `migration`.
+    pub async fn apply_migration(&self, migration: &Migration) -> Result<(), ORMError> {
+        migration::clean(&self.applied_migrations().await?)?;
+        let version = ORM::literal(migration.version.as_str());
+        let start = format!("insert into {} (version, name, checksum, applied_at, dirty) values ({}, {}, {}, {}, 1)", migration::TABLE,
+            version, ORM::literal(migration.name.as_str()), ORM::literal(migration.checksum().as_str()),
+            ORM::literal(crate::clock::timestamp(self.now(), false).as_str()));
+        let done = format!("update {} set dirty = 0 where version = {}", migration::TABLE, version);
+        self.run_migration(start, migration::statements(migration.up.as_str()), done).await
+    }
+
+    // Marks the migration as dirty, runs its down script and forgets it.
+    pub async fn revert_migration(&self, migration: &Migration) -> Result<(), ORMError> {
+        let statements = migration::statements(migration.down.as_str());
+        if statements.is_empty() {
+            return Err(ORMError::InvalidValue(format!("migration {}_{} has no down script", migration.version, migration.name)));
+        }
+        migration::clean(&self.applied_migrations().await?)?;
+        let version = ORM::literal(migration.version.as_str());
+        let start = format!("update {} set dirty = 1 where version = {}", migration::TABLE, version);
+        let done = format!("delete from {} where version = {}", migration::TABLE, version);
+        self.run_migration(start, statements, done).await
+    }
+
+    async fn run_migration(&self, start: String, statements: Vec<String>, done: String) -> Result<(), ORMError> {
+        self.query_update(start.as_str()).exec().await?;
+        for statement in statements {
+            self.query_update(statement.as_str()).exec().await?;
+        }
+        self.query_update(done.as_str()).exec().await?;
+        Ok(())
+    }
+
+    // Takes over the count of the `ormlib_last_change` table of older releases
+    // as the applied changes, see `migration`.
+    async fn adopt_last_change(&self) -> Result<Vec<AppliedMigration>, ORMError> {
+        let rows = self.query::<Row>("select last from ormlib_last_change").exec().await?;
+        let last: u32 = rows.first().and_then(|row| row.get(0)).unwrap_or_default();
+        let now = ORM::literal(crate::clock::timestamp(self.now(), false).as_str());
+        let statements: Vec<String> = (1..=last).map(|count| {
+            let migration = migration::change(count, "");
+            format!("insert into {} (version, name, checksum, applied_at) values ({}, {}, '', {})", migration::TABLE,
+                ORM::literal(migration.version.as_str()), ORM::literal(migration.name.as_str()), now)
+        }).collect();
+        for statement in statements {
+            self.query_update(statement.as_str()).exec().await?;
+        }
+        self.query_update("DROP TABLE ormlib_last_change").exec().await?;
+        self.applied_migrations().await
+    }
+
+    // Fails with `ORMError::SchemaMismatch` when the table of `T` doesn't
+    // match its fields, see `schema`.
+    pub async fn validate<T: TableDeserialize>(&self) -> Result<(), ORMError> {
+        let columns = self.columns_in(T::same_schema().as_deref(), T::same_name().as_str()).await?;
+        let mismatches = schema::mismatches::<T>(Dialect::Mysql, &columns);
+        match mismatches.is_empty() {
+            true => Ok(()),
+            false => Err(ORMError::SchemaMismatch(T::same_name(), mismatches)),
+        }
+    }
+
+    // The statements which make the table of `T` match its fields, none when it
+    // does.
+    pub async fn diff<T: TableDeserialize>(&self) -> Result<Vec<String>, ORMError> {
+        let (schema, name) = (T::same_schema(), T::same_name());
+        let columns = self.columns_in(schema.as_deref(), name.as_str()).await?;
+        let mismatches = schema::mismatches::<T>(Dialect::Mysql, &columns);
+        if mismatches.is_empty() {
+            return Ok(Vec::new());
+        }
+        let table = ORM::quote_table(schema, name.as_str());
+        if mismatches.contains(&Mismatch::MissingTable) {
+            return Ok(vec![schema::create_table::<T>(Dialect::Mysql, ORM::quote, table.as_str())]);
+        }
+        Ok(schema::mysql_alter::<T>(ORM::quote, table.as_str(), &columns))
+    }
+
+    // Updates only the changed fields of `tracked`, or nothing when none has
+    // changed, and takes it as saved. Returns the number of updated rows.
+    pub async fn save<T>(&self, tracked: &mut Tracked<T>) -> Result<usize, ORMError>
+        where T: for<'a> Deserialize<'a> + TableDeserialize + TableSerialize + Serialize + 'static
+    {
+        if !tracked.is_dirty() {
+            return Ok(0);
+        }
+        if let Some(hooks) = tracked.hooks() {
+            hooks.before_update().await?;
+        }
+        tracked.validate()?;
+        let (query, params, versioned) = self.update_query(&**tracked, Some(tracked.changed_fields()));
+        let r = QueryBuilder { params, ..self.query_update(query.as_str()) }.exec().await?;
+        if versioned && r == 0 {
+            return Err(ORMError::StaleEntity);
+        }
+        tracked.saved()?;
+        if let Some(hooks) = tracked.hooks() {
+            hooks.after_update().await?;
+        }
+        Ok(r)
+    }
+
+    // Walks the whole table of `T` in batches in `id` order, by keyset
+    // pagination, until `f` fails or the rows run out.
+    pub async fn find_each<T, F>(&self, batch_size: usize, mut f: F) -> Result<(), ORMError>
+        where T: for<'a> Deserialize<'a> + TableDeserialize + Serialize + Debug + 'static,
+              F: FnMut(Vec<T>) -> Result<(), ORMError>
+    {
+        let mut after: Option<Cursor> = None;
+        loop {
+            let page: Page<T> = self.find_all().paginate_by_key("id", after.as_ref(), batch_size).run().await?;
+            if !page.items.is_empty() {
+                f(page.items)?;
+            }
+            match page.next {
+                Some(next) => after = Some(next),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // The statements of the entities bind their values to placeholders, so
+    // that they are prepared once whatever the values.
+    fn insert_query<T>(&self, data: &T) -> (String, Vec<SqlValue>)
+        where T: TableDeserialize + TableSerialize + Serialize
+    {
+        let table_name = ORM::quote_table(data.schema(), data.name().as_str());
+        let types = serializer_types::to_string(data, ORM::quote, T::columns()).unwrap();
+        let (values, params) = serializer_values::to_placeholders(data, T::columns(), self.now()).unwrap();
+        (format!("insert into {table_name} {types} values {values}"), params)
+    }
+
+    // The update of `data`, or of only some of its fields, and whether it
+    // checks the version column.
+    fn update_query<T>(&self, data: &T, only: Option<Vec<String>>) -> (String, Vec<SqlValue>, bool)
+        where T: TableDeserialize + TableSerialize + Serialize
+    {
+        let table_name = ORM::quote_table(data.schema(), data.name().as_str());
+        let (key_value_str, mut params) = serializer_key_values::to_placeholders(data, ORM::quote, T::columns(), self.now(), only).unwrap();
+        // remove first and last char
+        let key_value = &key_value_str[1..key_value_str.len()-1];
+        params.push(serializer_values::field_to_value(data, T::columns(), "id").unwrap());
+        let id_column = ORM::quote("id");
+        let mut query: String = format!("update {table_name} set {key_value} where {id_column} = ?");
+        let version = T::columns().into_iter().find(|column| column.version);
+        if let Some(version) = &version {
+            params.push(serializer_values::field_to_value(data, T::columns(), version.name.as_str()).unwrap());
+            query = format!("{query} and {} = ?", ORM::quote(version.name.as_str()));
+        }
+        (query, params, version.is_some())
+    }
+
+    // Marks the row of `data` as removed when `T` soft deletes, unless forced.
+    fn delete_query<T>(&self, data: &T, force: bool) -> (String, Vec<SqlValue>)
+        where T: TableDeserialize + TableSerialize + Serialize
+    {
+        let table_name = ORM::quote_table(data.schema(), data.name().as_str());
+        let id = serializer_values::field_to_value(data, T::columns(), "id").unwrap();
+        let id_column = ORM::quote("id");
+        let column = match T::soft_delete() {
+            Some(column) if !force => column,
+            _ => return (format!("delete from {table_name} where {id_column} = ?"), vec![id]),
+        };
+        let now = match T::columns().into_iter().find(|c| c.name == column) {
+            Some(column) => serializer_values::timestamp_value(&column, self.now()),
+            None => SqlValue::Text(crate::clock::timestamp(self.now(), false)),
+        };
+        let column = ORM::quote(column.as_str());
+        (format!("update {table_name} set {column} = ? where {id_column} = ? and {column} is null"), vec![now, id])
+    }
+
+    // Clears the soft delete column of the row of `data`.
+    fn restore_query<T>(&self, data: &T) -> Result<(String, Vec<SqlValue>), ORMError>
+        where T: TableDeserialize + TableSerialize + Serialize
+    {
+        let column = T::soft_delete().ok_or_else(|| {
+            ORMError::InvalidValue(format!("{} can't be restored without #[table(soft_delete = \"column\")]", T::same_name()))
+        })?;
+        let table_name = ORM::quote_table(data.schema(), data.name().as_str());
+        let id = serializer_values::field_to_value(data, T::columns(), "id").unwrap();
+        let id_column = ORM::quote("id");
+        let column = ORM::quote(column.as_str());
+        Ok((format!("update {table_name} set {column} = null where {id_column} = ?"), vec![id]))
+    }
 }
 #[async_trait]
 impl ORMTrait<ORM> for ORM {
@@ -31,15 +625,16 @@ impl ORMTrait<ORM> for ORM {
     fn add<T>(&self, data: T) -> QueryBuilder<T, T, ORM>
         where T: for<'a> Deserialize<'a> + TableDeserialize + TableSerialize + Serialize + Debug + 'static
     {
-        let table_name = data.name();
-        let types = serializer_types::to_string(&data).unwrap();
-        let values = serializer_values::to_string(&data).unwrap();
-        let query: String = format!("insert into {table_name} {types} values {values}");
+        // The insert is built by `apply`, after the hooks and the validation.
         let qb = QueryBuilder::<T,T, ORM> {
-            query: query,
+            query: String::new(),
             entity: Default::default(),
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(Some(Operation::Insert(data))),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
         };
         qb
     }
@@ -72,15 +667,20 @@ impl ORMTrait<ORM> for ORM {
     fn find_one<T: TableDeserialize>(&self, id: u64) -> QueryBuilder<Option<T>, T, ORM>
         where T: TableDeserialize + TableSerialize + for<'a> Deserialize<'a> + 'static
     {
-        let table_name = T::same_name();
+        let table_name = ORM::quote_table(T::same_schema(), T::same_name().as_str());
+        let id_column = ORM::quote("id");
 
-        let query: String = format!("select * from {table_name} where id = {id}");
+        let filter = format!("{id_column} = ?");
 
         let qb = QueryBuilder::<Option<T>, T, ORM> {
-            query,
+            query: String::new(),
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: None,
+            select: Some(Select::new(table_name, Some(filter), ORM::not_deleted::<T>())),
+            params: vec![SqlValue::Integer(id as i64)],
         };
         qb
     }
@@ -90,73 +690,142 @@ impl ORMTrait<ORM> for ORM {
 
     {
 
-        let table_name = T::same_name();
-
-        let query: String = format!("select * from {table_name} where {query_where}");
+        let table_name = ORM::quote_table(T::same_schema(), T::same_name().as_str());
 
         let qb = QueryBuilder::<Vec<T>, T, ORM> {
-            query,
+            query: String::new(),
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: None,
+            select: Some(Select::new(table_name, Some(query_where.to_string()), ORM::not_deleted::<T>())),
+            params: Vec::new(),
         };
         qb
     }
 
     fn find_all<T>(&self) -> QueryBuilder<Vec<T>, T, ORM>
         where T: for<'a> Deserialize<'a> + TableDeserialize + Debug + 'static {
-        let table_name = T::same_name();
-
-        let query: String = format!("select * from {table_name}");
+        let table_name = ORM::quote_table(T::same_schema(), T::same_name().as_str());
 
         let qb = QueryBuilder::<Vec<T>, T, ORM> {
-            query,
+            query: String::new(),
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: None,
+            select: Some(Select::new(table_name, None, ORM::not_deleted::<T>())),
+            params: Vec::new(),
         };
         qb
     }
 
-    fn modify<T>(&self, data: T) -> QueryBuilder<usize, (), ORM>
+    fn modify<T>(&self, data: T) -> QueryBuilder<usize, T, ORM>
         where T: TableDeserialize + TableSerialize + Serialize + 'static
     {
-        let table_name = data.name();
-        let key_value_str = serializer_key_values::to_string(&data).unwrap();
-        // remove first and last char
-        let key_value = &key_value_str[1..key_value_str.len()-1];
-        let id = data.get_id();
-        let query: String = format!("update {table_name} set {key_value} where id = {id}");
-        let qb = QueryBuilder::<usize, (), ORM> {
-            query,
+        // The update is built by `run`, after the hooks and the validation.
+        let qb = QueryBuilder::<usize, T, ORM> {
+            query: String::new(),
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(Some(Operation::Update(data))),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
         };
         qb
     }
 
-    fn remove<T>(&self, data: T) -> QueryBuilder<usize, (), ORM>
+    fn remove<T>(&self, data: T) -> QueryBuilder<usize, T, ORM>
         where T: TableDeserialize + TableSerialize + Serialize + 'static
     {
-        let table_name = data.name();
-        let id = data.get_id();
-        let query: String = format!("delete from {table_name} where id = {id}");
-        let qb = QueryBuilder::<usize, (), ORM> {
-            query,
+        // The delete is built by `run`, after the hooks.
+        let qb = QueryBuilder::<usize, T, ORM> {
+            query: String::new(),
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(Some(Operation::Delete(data, false))),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
         };
         qb
     }
 
+    fn force_remove<T>(&self, data: T) -> QueryBuilder<'_, usize, T, ORM>
+        where T: TableDeserialize + TableSerialize + Serialize + 'static
+    {
+        // The delete is built by `run`, after the hooks.
+        QueryBuilder::<usize, T, ORM> {
+            query: String::new(),
+            entity: std::marker::PhantomData,
+            orm: self,
+            result: std::marker::PhantomData,
+            data: Mutex::new(Some(Operation::Delete(data, true))),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
+        }
+    }
+
+    fn restore<T>(&self, data: T) -> QueryBuilder<'_, usize, T, ORM>
+        where T: TableDeserialize + TableSerialize + Serialize + 'static
+    {
+        // The update is built by `run`, which fails when `T` doesn't soft delete.
+        QueryBuilder::<usize, T, ORM> {
+            query: String::new(),
+            entity: std::marker::PhantomData,
+            orm: self,
+            result: std::marker::PhantomData,
+            data: Mutex::new(Some(Operation::Restore(data))),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
+        }
+    }
+
+    fn count<T: TableDeserialize>(&self) -> Aggregate<'_, i64, T, ORM> {
+        Aggregate::new(self, "count", None)
+    }
+
+    fn exists<T: TableDeserialize>(&self) -> Aggregate<'_, bool, T, ORM> {
+        Aggregate::new(self, "exists", None)
+    }
+
+    fn sum<T: TableDeserialize, V: FromStr>(&self, column: &str) -> Aggregate<'_, Option<V>, T, ORM> {
+        Aggregate::new(self, "sum", Some(column))
+    }
+
+    fn avg<T: TableDeserialize, V: FromStr>(&self, column: &str) -> Aggregate<'_, Option<V>, T, ORM> {
+        Aggregate::new(self, "avg", Some(column))
+    }
+
+    fn min<T: TableDeserialize, V: FromStr>(&self, column: &str) -> Aggregate<'_, Option<V>, T, ORM> {
+        Aggregate::new(self, "min", Some(column))
+    }
+
+    fn max<T: TableDeserialize, V: FromStr>(&self, column: &str) -> Aggregate<'_, Option<V>, T, ORM> {
+        Aggregate::new(self, "max", Some(column))
+    }
+
+    fn group_by<T: TableDeserialize, K: FromStr>(&self, column: &str) -> GroupBy<'_, K, T, ORM> {
+        GroupBy::new(self, column)
+    }
+
     fn query<T>(&self, query: &str) -> QueryBuilder<Vec<T>, T, ORM> {
         let qb = QueryBuilder::<Vec<T>, T, ORM> {
             query: query.to_string(),
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
         };
         qb
     }
@@ -167,6 +836,10 @@ impl ORMTrait<ORM> for ORM {
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
         };
         qb
     }
@@ -196,6 +869,14 @@ impl ORMTrait<ORM> for ORM {
         escaped
     }
 
+    fn quote(identifier: &str) -> String {
+        format!("`{}`", identifier.replace("`", "``"))
+    }
+
+    fn json_path(column: &str, path: &str) -> String {
+        format!("json_unquote(json_extract({}, '{}'))", ORM::quote(column), path.replace('\'', "''"))
+    }
+
     fn escape_json(input: &str) -> String {
         let input = input.to_string();
         let mut escaped = input.clone();
@@ -227,68 +908,182 @@ impl ORMTrait<ORM> for ORM {
     }
 
     async fn change(&self, update_query: &str) -> anyhow::Result<(), ORMError> {
-        todo!()
+        let mut applied = self.applied_migrations().await?;
+        if !applied.iter().any(AppliedMigration::from_code) && self.tables().await?.iter().any(|table| table == "ormlib_last_change") {
+            applied = self.adopt_last_change().await?;
+        }
+        let mut change_count = self.change_count.lock().await;
+        *change_count += 1;
+        let migration = migration::change(*change_count, update_query);
+        if applied.iter().any(|applied| applied.version == migration.version) {
+            return Ok(());
+        }
+        self.apply_migration(&migration).await
     }
 }
 
 impl<T> QueryBuilder<'_, usize, T, ORM>{
     pub async fn exec(&self) -> Result<usize, ORMError> {
-        log::debug!("{:?}", self.query);
-        let mut conn = self.orm.conn.lock().await;
-        if conn.is_none() {
-            return Err(ORMError::NoConnection);
-        }
-        let conn = conn.as_mut().unwrap();
-        let r = conn.query_iter(self.query.as_str()).await.map(|result| {
-            result.affected_rows()
-        })?;
-        Ok(r as usize)
+        let timer = self.orm.timer(self.query.as_str());
+        self.orm.invalidate(self.query.as_str());
+        let r = async {
+            let mut conn = self.orm.connection().await;
+            if conn.is_none() {
+                return Err(ORMError::NoConnection);
+            }
+            let conn = conn.as_mut().unwrap();
+            self.orm.deadline(conn.id(), self.orm.timeout(self.timeout), async {
+                // Statements without values, such as scripts of several
+                // statements, go through the text protocol.
+                let r = match self.params.is_empty() {
+                    true => conn.query_iter(self.query.as_str()).await?.affected_rows(),
+                    false => conn.exec_iter(self.query.as_str(), params(&self.params)).await?.affected_rows(),
+                };
+                Ok(r as usize)
+            }).await
+        }.await;
+        timer.finish(r.as_ref().copied());
+        self.orm.invalidate(self.query.as_str());
+        r
     }
 }
 
 impl<T> QueryBuilder<'_, T,T, ORM>{
     pub async fn apply(&self) -> Result<T, ORMError>
-        where T: for<'a> Deserialize<'a> + TableDeserialize + TableSerialize + Debug + 'static
+        where T: for<'a> Deserialize<'a> + TableDeserialize + TableSerialize + Serialize + Debug + 'static
     {
-        log::debug!("{:?}", self.query);
-        let r = {
-            let mut conn = self.orm.conn.lock().await;
+        let (mut query, mut values) = (self.query.clone(), self.params.clone());
+        let mut id = None;
+        let mut data = self.data.lock().await;
+        if let Some(Operation::Insert(data)) = &mut *data {
+            if let Some(hooks) = data.hooks() {
+                hooks.before_insert().await?;
+            }
+            data.validate()?;
+            (query, values) = self.orm.insert_query(data);
+            // There is no insert id to find the row of an entity which brings its own key.
+            if !serializer_values::is_generated(&T::columns(), "id") {
+                id = Some(serializer_values::field_to_value(data, T::columns(), "id").unwrap());
+            }
+        }
+        let timer = self.orm.timer(query.as_str());
+        self.orm.invalidate(query.as_str());
+        let result = async {
+            let mut conn = self.orm.connection().await;
             if conn.is_none() {
                 return Err(ORMError::NoConnection);
             }
             let conn = conn.as_mut().unwrap();
-            let r = conn.query_iter(self.query.as_str()).await.map(|result| {
-                result.last_insert_id()
-            })?;
-            if r.is_none() {
+            self.orm.deadline(conn.id(), self.orm.timeout(self.timeout), async {
+                let r = conn.exec_iter(query.as_str(), params(&values)).await?;
+                Ok((r.affected_rows() as usize, r.last_insert_id()))
+            }).await
+        }.await;
+        timer.finish(result.as_ref().map(|(rows, _)| *rows));
+        self.orm.invalidate(query.as_str());
+        let r = {
+            let (_rows, r) = result?;
+            if let Some(id) = id {
+                id
+            } else if let Some(r) = r {
+                SqlValue::Integer(r as i64)
+            } else {
                 return Err(ORMError::InsertError);
             }
-            r.unwrap()
 
         };
-        let rows: Vec<T> = self.orm.find_many(format!("id = {}", r).as_str()).run().await?;
+        let rows: Vec<T> = QueryBuilder { timeout: self.timeout, params: vec![r], ..self.orm.find_many(format!("{} = ?", ORM::quote("id")).as_str()) }.run().await?;
         if rows.len() == 0 {
             return Err(ORMError::InsertError);
         }
         let t_opt = rows.into_iter().next();
         match t_opt {
-            Some(t) => Ok(t),
+            Some(mut t) => {
+                if let Some(hooks) = t.hooks() {
+                    hooks.after_insert().await?;
+                }
+                Ok(t)
+            }
             None => Err(ORMError::InsertError),
         }
 
     }
 }
 
-impl<T> QueryBuilder<'_, usize,T, ORM> {
+impl<T> QueryBuilder<'_, usize, T, ORM> {
+    // The statement of `run`, retried when it fails with a transient error.
+    async fn execute(&self, query: &str, values: &[SqlValue]) -> Result<usize, ORMError> {
+        let timer = self.orm.timer(query);
+        self.orm.invalidate(query);
+        let (orm, statement, timeout) = (self.orm, query, self.orm.timeout(self.timeout));
+        let retryable = |e: &ORMError| e.is_transient() && !e.is_deadlock();
+        let result = retry(orm.statement_retry_policy(), retryable, move || async move {
+            let mut conn = orm.connection().await;
+            if conn.is_none() {
+                return Err(ORMError::NoConnection);
+            }
+            let conn = conn.as_mut().unwrap();
+            orm.deadline(conn.id(), timeout, async {
+                let r = conn.exec_iter(statement, params(values)).await?;
+                Ok(r.affected_rows() as usize)
+            }).await
+        }).await;
+        timer.finish(result.as_ref().copied());
+        self.orm.invalidate(query);
+        result
+    }
+}
+
+impl QueryBuilder<'_, usize, (), ORM> {
     pub async fn run(&self) -> Result<usize, ORMError> {
-        log::debug!("{:?}", self.query);
-        let mut conn = self.orm.conn.lock().await;
-        if conn.is_none() {
-            return Err(ORMError::NoConnection);
+        self.execute(self.query.as_str(), &self.params).await
+    }
+}
+
+impl<T> QueryBuilder<'_, usize, T, ORM>
+    where T: TableDeserialize + TableSerialize + Serialize + 'static
+{
+    // Runs the statement of the entity between its hooks.
+    pub async fn run(&self) -> Result<usize, ORMError> {
+        let (mut query, mut params) = (self.query.clone(), self.params.clone());
+        // an update which checks the version column, so no affected row is a conflict
+        let mut versioned = false;
+        let mut data = self.data.lock().await;
+        match &mut *data {
+            Some(Operation::Update(data)) => {
+                if let Some(hooks) = data.hooks() {
+                    hooks.before_update().await?;
+                }
+                data.validate()?;
+                (query, params, versioned) = self.orm.update_query(data, None);
+            }
+            Some(Operation::Delete(data, force)) => {
+                if let Some(hooks) = data.hooks() {
+                    hooks.before_delete().await?;
+                }
+                (query, params) = self.orm.delete_query(data, *force);
+            }
+            Some(Operation::Restore(data)) => (query, params) = self.orm.restore_query(data)?,
+            _ => {}
         }
-        let conn = conn.as_mut().unwrap();
-        let r = conn.query_iter(self.query.as_str()).await?;
-        Ok(r.affected_rows() as usize)
+        let r = self.execute(query.as_str(), &params).await?;
+        if versioned && r == 0 {
+            return Err(ORMError::StaleEntity);
+        }
+        match &mut *data {
+            Some(Operation::Update(data)) => {
+                if let Some(hooks) = data.hooks() {
+                    hooks.after_update().await?;
+                }
+            }
+            Some(Operation::Delete(data, _)) => {
+                if let Some(hooks) = data.hooks() {
+                    hooks.after_delete().await?;
+                }
+            }
+            _ => {}
+        }
+        Ok(r)
     }
 }
 
@@ -296,9 +1091,42 @@ impl<T> QueryBuilder<'_, usize,T, ORM> {
 impl<T> QueryBuilder<'_, Option<T>,T, ORM>
     where T: for<'a> Deserialize<'a> + TableDeserialize + Debug + 'static
 {
+    pub fn with_deleted(&self) -> QueryBuilder<'_, Option<T>, T, ORM> {
+        QueryBuilder::<Option<T>,T, ORM> {
+            query: self.query.clone(),
+            entity: std::marker::PhantomData,
+            orm: self.orm,
+            result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: self.timeout,
+            select: self.select.clone().map(|select| Select { deleted: None, ..select }),
+            params: self.params.clone(),
+        }
+    }
+
+    pub fn only_deleted(&self) -> QueryBuilder<'_, Option<T>, T, ORM> {
+        QueryBuilder::<Option<T>,T, ORM> {
+            query: self.query.clone(),
+            entity: std::marker::PhantomData,
+            orm: self.orm,
+            result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: self.timeout,
+            select: self.select.clone().map(|select| Select { deleted: ORM::deleted::<T>(), ..select }),
+            params: self.params.clone(),
+        }
+    }
+
+    // Runs the query, with a snapshot of the entity for `save`.
+    pub async fn run_tracked(&self) -> Result<Option<Tracked<T>>, ORMError>
+        where T: Serialize
+    {
+        Ok(self.run().await?.map(Tracked::new))
+    }
+
     pub async fn run(&self) -> Result<Option<T>, ORMError> {
 
-        let rows  = self.orm.query(self.query.clone().as_str()).exec().await?;
+        let rows = self.orm.find_rows(self.sql().as_str(), &self.params, self.timeout).await?;
         let columns: Vec<String> =T::fields();
         if rows.len() == 0 {
             return Ok(None);
@@ -322,7 +1150,10 @@ impl<T> QueryBuilder<'_, Option<T>,T, ORM>
             }
             let user_str = format!("{{{}}}", column_str.join(","));
             // log::debug!("zzz{}", user_str);
-            let user: T = deserializer_key_values::from_str(&user_str).unwrap();
+            let mut user: T = deserializer_key_values::from_str(&user_str, T::columns()).unwrap();
+            if let Some(hooks) = user.hooks() {
+                hooks.after_load().await?;
+            }
             Ok(Some(user))
 
         }
@@ -333,57 +1164,179 @@ impl<T> QueryBuilder<'_, Option<T>,T, ORM>
 impl<R> QueryBuilder<'_, Vec<Row>,R, ORM> {
     pub async fn exec(&self) -> Result<Vec<Row>, ORMError>
     {
-        log::debug!("{:?}", self.query);
-        let mut conn = self.orm.conn.lock().await;
+        let timer = self.orm.timer(self.query.as_str());
+        let rows = retry(self.orm.statement_retry_policy(), |e: &ORMError| e.is_transient() && !e.is_deadlock(), move || self.rows()).await;
+        timer.finish(rows.as_ref().map(Vec::len));
+        rows
+    }
+
+    async fn rows(&self) -> Result<Vec<Row>, ORMError>
+    {
+        let mut conn = self.orm.connection().await;
         if conn.is_none() {
             return Err(ORMError::NoConnection);
         }
         let conn = conn.as_mut().unwrap();
-        let stmt_result = conn.query_iter( self.query.as_str()).await;
-         if stmt_result.is_err() {
-            let e = stmt_result.err().unwrap();
-            log::error!("{:?}", e);
-            return Err(ORMError::MySQLError(e));
-        }
-        let mut stmt = stmt_result.unwrap();
-        let columns =stmt.columns();
-        let columns = columns.unwrap();
-        let columns_type: Vec<bool> = columns.iter().map(|column| {
-            column.column_type().is_numeric_type()
-        }).collect();
-        let mut result: Vec<Row> = Vec::new();
-        // println!("{:?}", columns_type);
-        stmt.for_each(|row| {
-            let mut i = 0;
-            let mut r: Row = Row::new();
-            loop {
-                if i > columns_type.len() - 1 {
-                    break;
-                }
-                if columns_type[i] {
-                    let res: Option<i32>= row.get(i);
-                    if res.is_none() {
-                        break;
-                    }
-                    r.set(i.try_into().unwrap(), res);
-                } else {
-                    let res: Option<String>= row.get(i);
-                    if res.is_none() {
-                        break;
+        self.orm.deadline(conn.id(), self.orm.timeout(self.timeout), async move {
+            // Prepared, so that the statement is parsed once while it stays in the
+            // statement cache of the connection.
+            let stmt_result = conn.exec_iter(self.query.as_str(), params(&self.params)).await;
+             if stmt_result.is_err() {
+                let e = stmt_result.err().unwrap();
+                log::error!("{:?}", e);
+                return Err(ORMError::MySQLError(e));
+            }
+            let mut stmt = stmt_result.unwrap();
+            let columns =stmt.columns();
+            let columns = columns.unwrap();
+            let columns_type: Vec<ColumnType> = columns.iter().map(|column| column.column_type()).collect();
+            // Binary strings are read as hex text, see `Row`; JSON, date and time
+            // columns report the binary character set too.
+            let columns_binary: Vec<bool> = columns.iter().map(|column| {
+                column.character_set() == BINARY_CHARSET && !column.column_type().is_numeric_type()
+                    && !matches!(column.column_type(), ColumnType::MYSQL_TYPE_DATE | ColumnType::MYSQL_TYPE_TIME |
+                        ColumnType::MYSQL_TYPE_DATETIME | ColumnType::MYSQL_TYPE_TIMESTAMP | ColumnType::MYSQL_TYPE_YEAR |
+                        ColumnType::MYSQL_TYPE_JSON)
+            }).collect();
+            let mut result: Vec<Row> = Vec::new();
+            stmt.for_each(|row| {
+                let mut r: Row = Row::new();
+                for i in 0..columns_type.len() {
+                    if let Some(value) = row.as_ref(i) {
+                        r.set(i.try_into().unwrap(), value_text(value, columns_type[i], columns_binary[i]));
                     }
-                    r.set(i.try_into().unwrap(), res);
                 }
-                i = i + 1;
-            }
-            result.push(r);
-        }).await?;
+                result.push(r);
+            }).await?;
 
-        // log::debug!("{:?}", result);
+            // log::debug!("{:?}", result);
 
-        Ok(result)
+            Ok(result)
+        }).await
+    }
+
+
+}
+
+impl<T: TableDeserialize> Aggregate<'_, i64, T, ORM> {
+    pub async fn run(&self) -> Result<i64, ORMError> {
+        let rows = self.orm.query::<Row>(self.sql()?.as_str()).exec().await?;
+        aggregate::count(rows)
+    }
+}
+
+impl<T: TableDeserialize> Aggregate<'_, bool, T, ORM> {
+    pub async fn run(&self) -> Result<bool, ORMError> {
+        let rows = self.orm.query::<Row>(self.sql()?.as_str()).exec().await?;
+        Ok(!rows.is_empty())
+    }
+}
+
+impl<T: TableDeserialize, V: FromStr> Aggregate<'_, Option<V>, T, ORM> {
+    pub async fn run(&self) -> Result<Option<V>, ORMError> {
+        let rows = self.orm.query::<Row>(self.sql()?.as_str()).exec().await?;
+        aggregate::value(rows)
+    }
+}
+
+impl<T: TableDeserialize, K: FromStr> Aggregate<'_, Vec<(K, i64)>, T, ORM> {
+    pub async fn run(&self) -> Result<Vec<(K, i64)>, ORMError> {
+        let rows = self.orm.query::<Row>(self.sql()?.as_str()).exec().await?;
+        aggregate::groups(rows)
+    }
+}
+
+impl<'a> Session<'a, ORM> {
+    // Loads the entity through the identity map.
+    pub async fn find_one<T>(&mut self, id: u64) -> Result<Option<T>, ORMError>
+        where T: for<'b> Deserialize<'b> + TableDeserialize + TableSerialize + Debug + Clone + Send + 'static
+    {
+        if let Some(entity) = self.cached::<T>(id.to_string()) {
+            return Ok(Some(entity));
+        }
+        let entity: Option<T> = self.orm.find_one(id).run().await?;
+        if let Some(entity) = &entity {
+            self.cache(id.to_string(), entity.clone());
+        }
+        Ok(entity)
+    }
+
+    pub fn add<T>(&mut self, entity: T)
+        where T: for<'b> Deserialize<'b> + TableDeserialize + TableSerialize + Serialize + Debug + Send + Sync + 'static
+    {
+        let orm = self.orm;
+        self.enqueue(Order::Insert, T::same_name(), move || Box::pin(async move {
+            orm.add(entity).apply().await.map(|_| ())
+        }));
+    }
+
+    // Also replaces the entity in the identity map.
+    pub fn modify<T>(&mut self, entity: T) -> Result<(), ORMError>
+        where T: TableDeserialize + TableSerialize + Serialize + Clone + Send + Sync + 'static
+    {
+        self.cache(Self::id_of(&entity)?, entity.clone());
+        let orm = self.orm;
+        self.enqueue(Order::Update, T::same_name(), move || Box::pin(async move {
+            orm.modify(entity).run().await.map(|_| ())
+        }));
+        Ok(())
     }
 
+    pub fn remove<T>(&mut self, entity: T) -> Result<(), ORMError>
+        where T: TableDeserialize + TableSerialize + Serialize + Send + Sync + 'static
+    {
+        self.evict::<T>(Self::id_of(&entity)?);
+        let orm = self.orm;
+        self.enqueue(Order::Delete, T::same_name(), move || Box::pin(async move {
+            orm.remove(entity).run().await.map(|_| ())
+        }));
+        Ok(())
+    }
 
+    // Runs the queued writes in one transaction, or in a savepoint within
+    // `transaction`, ordered by the foreign keys of their tables, see
+    // `session`. On an error it is rolled back, and the identity map is
+    // cleared as it may hold unsaved changes.
+    pub async fn flush(&mut self) -> Result<(), ORMError> {
+        let mut references = HashMap::new();
+        for table in self.pending_tables() {
+            let foreign_keys = self.orm.foreign_keys(table.as_str()).await?;
+            references.insert(table, foreign_keys.into_iter().map(|fk| fk.referenced_table).collect::<Vec<_>>());
+        }
+        let pending = self.take_pending(&references);
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let r = self.orm.atomically(async {
+            for run in pending {
+                run.await?;
+            }
+            Ok(())
+        }).await;
+        if r.is_err() {
+            self.clear();
+        }
+        r
+    }
+}
+
+impl<T> Paginate<'_, T, ORM> {
+    pub async fn run(&self) -> Result<Page<T>, ORMError>
+        where T: for<'a> Deserialize<'a> + TableDeserialize + Serialize + Debug + 'static
+    {
+        let query = QueryBuilder::<Vec<T>, T, ORM> {
+            query: self.query().to_string(),
+            entity: std::marker::PhantomData,
+            orm: self.orm,
+            result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: None,
+            select: None,
+            params: self.params().to_vec(),
+        };
+        let items: Vec<T> = query.run().await?;
+        self.page(items)
+    }
 }
 
 impl<T> QueryBuilder<'_, Vec<T>,T, ORM> {
@@ -392,7 +1345,7 @@ impl<T> QueryBuilder<'_, Vec<T>,T, ORM> {
     {
 
         let mut result: Vec<T> = Vec::new();
-        let rows  = self.orm.query(self.query.clone().as_str()).exec().await?;
+        let rows = self.orm.find_rows(self.sql().as_str(), &self.params, self.timeout).await?;
         let columns: Vec<String> =T::fields();
         for row in rows {
             let mut column_str: Vec<String> = Vec::new();
@@ -413,14 +1366,16 @@ impl<T> QueryBuilder<'_, Vec<T>,T, ORM> {
             }
             let user_str = format!("{{{}}}", column_str.join(","));
             // log::info!("{}", user_str);
-            let user_result: std::result::Result<T, serializer_error::Error> = deserializer_key_values::from_str(&user_str);
+            let user_result: std::result::Result<T, serializer_error::Error> = deserializer_key_values::from_str(&user_str, T::columns());
             match user_result {
-                Ok(user) => {
+                Ok(mut user) => {
+                    if let Some(hooks) = user.hooks() {
+                        hooks.after_load().await?;
+                    }
                     result.push(user);
                 }
                 Err(e) => {
                     log::error!("{:?}", e);
-                    log::error!("{}", user_str);
                     return Err(ORMError::Unknown);
                 }
             }
@@ -430,6 +1385,50 @@ impl<T> QueryBuilder<'_, Vec<T>,T, ORM> {
         Ok(result)
     }
 
+    // Runs the query, with snapshots of the entities for `save`.
+    pub async fn run_tracked(&self) -> Result<Vec<Tracked<T>>, ORMError>
+        where T: for<'a> Deserialize<'a> + TableDeserialize + Serialize + Debug + 'static
+    {
+        Ok(self.run().await?.into_iter().map(Tracked::new).collect())
+    }
+
+    // Includes soft-deleted rows.
+    pub fn with_deleted(&self) -> QueryBuilder<'_, Vec<T>, T, ORM>
+        where T: TableDeserialize
+    {
+        QueryBuilder::<Vec<T>,T, ORM> {
+            query: self.query.clone(),
+            entity: std::marker::PhantomData,
+            orm: self.orm,
+            result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: self.timeout,
+            select: self.select.clone().map(|select| Select { deleted: None, ..select }),
+            params: self.params.clone(),
+        }
+    }
+
+    // Only takes soft-deleted rows.
+    pub fn only_deleted(&self) -> QueryBuilder<'_, Vec<T>, T, ORM>
+        where T: TableDeserialize
+    {
+        QueryBuilder::<Vec<T>,T, ORM> {
+            query: self.query.clone(),
+            entity: std::marker::PhantomData,
+            orm: self.orm,
+            result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: self.timeout,
+            select: self.select.clone().map(|select| Select { deleted: ORM::deleted::<T>(), ..select }),
+            params: self.params.clone(),
+        }
+    }
+
+    // The first `page_size` rows after the cursor in `key` order, see `pagination`.
+    pub fn paginate_by_key(&self, key: &str, after: Option<&Cursor>, page_size: usize) -> Paginate<'_, T, ORM> {
+        Paginate::new(self.orm, self.sql().as_str(), &self.params, key, after, page_size)
+    }
+
     pub fn limit(&self, limit: i32) -> QueryBuilder<Vec<T>, T, ORM> {
 
         let qb =  QueryBuilder::<Vec<T>,T, ORM> {
@@ -437,8 +1436,36 @@ impl<T> QueryBuilder<'_, Vec<T>,T, ORM> {
             entity: std::marker::PhantomData,
             orm: self.orm,
             result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: self.timeout,
+            select: self.select.clone().map(|select| Select { limit: Some(limit), ..select }),
+            params: self.params.clone(),
         };
         qb
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_text() {
+        assert_eq!(None, value_text(&Value::NULL, ColumnType::MYSQL_TYPE_STRING, false));
+        assert_eq!(Some("-7".to_string()), value_text(&Value::Int(-7), ColumnType::MYSQL_TYPE_LONG, false));
+        assert_eq!(Some("12.30".to_string()), value_text(&Value::Bytes(b"12.30".to_vec()), ColumnType::MYSQL_TYPE_NEWDECIMAL, false));
+        assert_eq!(Some("0aff".to_string()), value_text(&Value::Bytes(vec![10, 255]), ColumnType::MYSQL_TYPE_STRING, true));
+        assert_eq!(Some("2023-09-08".to_string()), value_text(&Value::Date(2023, 9, 8, 0, 0, 0, 0), ColumnType::MYSQL_TYPE_DATE, false));
+        assert_eq!(Some("2023-09-08 13:33:22".to_string()), value_text(&Value::Date(2023, 9, 8, 13, 33, 22, 0), ColumnType::MYSQL_TYPE_DATETIME, false));
+        assert_eq!(Some("2023-09-08 13:33:22.000500".to_string()), value_text(&Value::Date(2023, 9, 8, 13, 33, 22, 500), ColumnType::MYSQL_TYPE_TIMESTAMP, false));
+        assert_eq!(Some("-26:01:02".to_string()), value_text(&Value::Time(true, 1, 2, 1, 2, 0), ColumnType::MYSQL_TYPE_TIME, false));
+    }
+
+    #[test]
+    fn test_utc_param() {
+        assert_eq!(("mysql://localhost/app".to_string(), false), utc_param("mysql://localhost/app"));
+        assert_eq!(("mysql://localhost/app".to_string(), true), utc_param("mysql://localhost/app?utc=true"));
+        assert_eq!(("mysql://localhost/app?stmt_cache_size=8".to_string(), false),
+            utc_param("mysql://localhost/app?utc=false&stmt_cache_size=8"));
+    }
+}
@@ -0,0 +1,154 @@
+// Keyset pagination: a page is the rows after the key of the last row of the
+// previous page, in key order, so no page scans the rows before it as OFFSET
+// would.
+//
+//     let page: Page<User> = conn.find_all().paginate_by_key("id", None, 20).run().await?;
+//     let next: Page<User> = conn.find_all().paginate_by_key("id", page.next.as_ref(), 20).run().await?;
+//
+// The key has to be unique, e.g. the primary key. The cursor of the next page
+// is opaque text which can be handed to clients and parsed back.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::{ORMError, ORMTrait, SqlValue};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor(serde_json::Value);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    // `None` on the last page
+    pub next: Option<Cursor>,
+}
+
+pub struct Paginate<'a, T, O: ORMTrait<O>> {
+    pub(crate) orm: &'a O,
+    query: String,
+    params: Vec<SqlValue>,
+    key: String,
+    page_size: usize,
+    entity: PhantomData<T>,
+}
+
+impl Cursor {
+    // The cursor after `entity`, from the serde form of its key.
+    pub(crate) fn of<T: Serialize>(entity: &T, key: &str) -> Result<Self, ORMError> {
+        let value = serde_json::to_value(entity).map_err(|e| ORMError::InvalidValue(e.to_string()))?;
+        match value.get(key) {
+            Some(value @ (serde_json::Value::Number(_) | serde_json::Value::String(_))) => Ok(Cursor(value.clone())),
+            _ => Err(ORMError::InvalidValue(format!("no key {} to paginate by", key))),
+        }
+    }
+
+    fn value(&self) -> SqlValue {
+        match &self.0 {
+            serde_json::Value::String(text) => SqlValue::Text(text.clone()),
+            value => match (value.as_i64(), value.as_f64()) {
+                (Some(int), _) => SqlValue::Integer(int),
+                (None, Some(real)) => SqlValue::Real(real),
+                _ => SqlValue::Text(value.to_string()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0.to_string().bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+// Only takes numbers and strings, the values a key is compared with.
+impl FromStr for Cursor {
+    type Err = ORMError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ORMError::InvalidValue(format!("invalid cursor: {}", s));
+        if !s.len().is_multiple_of(2) || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..s.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        match serde_json::from_slice(bytes.as_slice()) {
+            Ok(value @ (serde_json::Value::Number(_) | serde_json::Value::String(_))) => Ok(Cursor(value)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl<'a, T, O: ORMTrait<O>> Paginate<'a, T, O> {
+    // The page of the rows of `query` after the cursor, which is bound after
+    // the values of `query`.
+    pub(crate) fn new(orm: &'a O, query: &str, params: &[SqlValue], key: &str, after: Option<&Cursor>, page_size: usize) -> Self {
+        let column = O::quote(key);
+        let mut params = params.to_vec();
+        let condition = match after {
+            Some(after) => {
+                params.push(after.value());
+                format!(" where {column} > ?")
+            }
+            None => String::new(),
+        };
+        // One row more than the page tells whether there is a next page.
+        let query = format!("select * from ({query}) as page{condition} order by {column} limit {}", page_size + 1);
+        Paginate {
+            orm,
+            query,
+            params,
+            key: key.to_string(),
+            page_size,
+            entity: PhantomData,
+        }
+    }
+
+    pub(crate) fn query(&self) -> &str {
+        self.query.as_str()
+    }
+
+    pub(crate) fn params(&self) -> &[SqlValue] {
+        self.params.as_slice()
+    }
+
+    pub(crate) fn page(&self, mut items: Vec<T>) -> Result<Page<T>, ORMError>
+        where T: Serialize
+    {
+        if items.len() <= self.page_size {
+            return Ok(Page { items, next: None });
+        }
+        items.truncate(self.page_size);
+        let next = match items.last() {
+            Some(last) => Some(Cursor::of(last, self.key.as_str())?),
+            None => None,
+        };
+        Ok(Page { items, next })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor() {
+        let cursor = Cursor(serde_json::json!(42));
+        assert_eq!(cursor.to_string(), "3432");
+        assert_eq!(Cursor::from_str("3432").unwrap(), cursor);
+        assert_eq!(SqlValue::Integer(42), cursor.value());
+        assert_eq!(SqlValue::Real(1.5), Cursor(serde_json::json!(1.5)).value());
+        let cursor = Cursor(serde_json::json!("a\"b"));
+        assert_eq!(Cursor::from_str(cursor.to_string().as_str()).unwrap(), cursor);
+        assert!(Cursor::from_str("7b7d").is_err());
+        assert!(Cursor::from_str("zz").is_err());
+        assert!(Cursor::from_str("343").is_err());
+    }
+}
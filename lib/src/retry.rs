@@ -0,0 +1,166 @@
+// Retries on transient errors: a database busy or locked on SQLite, a deadlock
+// or lock wait timeout on MySQL. Off until `set_retry_policy`.
+//
+//     conn.set_retry_policy(RetryPolicy::default());
+//
+// `find_*` queries, updates, removals and `query_update(..).run()` statements
+// are retried on their own, as running them again gives the same result; with
+// `run` the caller tells that its statement is such one. Inserts and `exec()`
+// statements aren't, since they may not be idempotent.
+//
+// Within `transaction` or a session flush no statement is retried on its own:
+// `transaction` rolls back and runs again as a whole, and a flush fails. As a
+// MySQL deadlock rolls back the whole transaction, only `transaction` retries
+// it.
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use crate::ORMError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    // including the first one, so 1 is no retry
+    pub max_attempts: u32,
+    // the wait before the first retry, doubled before each next one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // waits a random part of the backoff, between half and all of it, so that
+    // clients which failed together don't retry together
+    pub jitter: bool,
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    // The wait before the given retry, from 1.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff);
+        match self.jitter {
+            true => backoff.mul_f64(0.5 + random() * 0.5),
+            false => backoff,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            jitter: true,
+        }
+    }
+}
+
+impl ORMError {
+    // Whether the error may go away when the statement or transaction runs
+    // again, by the error code of the database.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            #[cfg(feature = "sqlite")]
+            ORMError::RusqliteError(rusqlite::Error::SqliteFailure(e, _)) => {
+                matches!(e.code, rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+            }
+            #[cfg(feature = "mysql")]
+            ORMError::MySQLError(mysql_async::Error::Server(e)) => {
+                e.code == ER_LOCK_DEADLOCK || e.code == ER_LOCK_WAIT_TIMEOUT
+            }
+            _ => false,
+        }
+    }
+
+    // Whether the error ended the transaction which the statement ran in, so
+    // that only the whole transaction can run again.
+    pub(crate) fn is_deadlock(&self) -> bool {
+        match self {
+            #[cfg(feature = "mysql")]
+            ORMError::MySQLError(mysql_async::Error::Server(e)) => e.code == ER_LOCK_DEADLOCK,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "mysql")]
+const ER_LOCK_WAIT_TIMEOUT: u16 = 1205;
+#[cfg(feature = "mysql")]
+const ER_LOCK_DEADLOCK: u16 = 1213;
+
+// Runs `f` until it succeeds, fails with an error which `retryable` rejects,
+// or runs out of attempts.
+pub(crate) async fn retry<R, F, Fut>(policy: RetryPolicy, retryable: impl Fn(&ORMError) -> bool, mut f: F) -> Result<R, ORMError>
+    where F: FnMut() -> Fut,
+          Fut: Future<Output = Result<R, ORMError>>
+{
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Err(e) if attempt < policy.max_attempts && retryable(&e) => {
+                let backoff = policy.backoff(attempt);
+                log::warn!("retrying after {:?} in {:?}", e, backoff);
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Between 0 and 1.
+fn random() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy { max_attempts: 10, initial_backoff: Duration::from_millis(10), max_backoff: Duration::from_millis(50), jitter: false };
+        let backoffs: Vec<u128> = (1..=4).map(|retry| policy.backoff(retry).as_millis()).collect();
+        assert_eq!(vec![10, 20, 40, 50], backoffs);
+        let policy = RetryPolicy { jitter: true, ..policy };
+        for retry in 1..=4 {
+            let backoff = policy.backoff(retry);
+            assert!(backoff >= Duration::from_millis(5) && backoff <= Duration::from_millis(50));
+        }
+        assert!(!ORMError::InsertError.is_transient());
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = RetryPolicy { initial_backoff: Duration::ZERO, ..RetryPolicy::default() };
+        let mut attempts = 0;
+        let r = retry(policy, |e| matches!(e, ORMError::Unknown), || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                match attempt {
+                    1 | 2 => Err(ORMError::Unknown),
+                    _ => Ok(attempt),
+                }
+            }
+        }).await;
+        assert_eq!(3, r.unwrap());
+
+        let mut attempts = 0;
+        let r: Result<(), ORMError> = retry(policy, |e| matches!(e, ORMError::Unknown), || {
+            attempts += 1;
+            async { Err(ORMError::Unknown) }
+        }).await;
+        assert!(r.is_err());
+        assert_eq!(5, attempts);
+    }
+}
@@ -0,0 +1,117 @@
+// UUID fields of the `uuid` crate and decimal fields of the `rust_decimal`
+// crate.
+//
+// UUIDs are stored as hyphenated text, which fits SQLite TEXT and MySQL
+// CHAR(36) columns, or as 16 raw bytes for `#[column(binary)]` fields, which
+// fits SQLite BLOB and MySQL BINARY(16) columns. Decimals are stored as quoted
+// text, which fits SQLite TEXT and MySQL DECIMAL columns, so that no value ever
+// goes through a float on its way to the database or back.
+//
+// Binary values are read from the database as hex text, see the row readers.
+
+use crate::serializer_error::{Error, Result};
+use crate::SqlValue;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScalarKind {
+    #[cfg(feature = "uuid")]
+    Uuid,
+    #[cfg(feature = "rust_decimal")]
+    Decimal,
+}
+
+// Recognizes the types by the Rust type of the field, as recorded by the
+// derive, e.g. `Option<uuid::Uuid>`.
+pub fn kind(rust_type: &str) -> Option<ScalarKind> {
+    let mut ty: String = rust_type.chars().filter(|c| !c.is_whitespace()).collect();
+    if let Some(inner) = ty.strip_prefix("Option<").and_then(|t| t.strip_suffix('>')) {
+        ty = inner.to_string();
+    }
+    match ty.rsplit("::").next().unwrap_or_default() {
+        #[cfg(feature = "uuid")]
+        "Uuid" => Some(ScalarKind::Uuid),
+        #[cfg(feature = "rust_decimal")]
+        "Decimal" => Some(ScalarKind::Decimal),
+        _ => None,
+    }
+}
+
+// Converts the serde form of a field into the value of its column.
+#[cfg_attr(not(feature = "uuid"), allow(unused_variables))]
+pub fn to_sql(kind: ScalarKind, value: serde_json::Value, binary: bool) -> Result<SqlValue> {
+    let text = match value {
+        serde_json::Value::Null => return Ok(SqlValue::Null),
+        serde_json::Value::String(text) => text,
+        // `rust_decimal` may be built to serialize as a number.
+        serde_json::Value::Number(number) => number.to_string(),
+        value => return Err(Error::Message(format!("unexpected value: {}", value))),
+    };
+    match kind {
+        #[cfg(feature = "uuid")]
+        ScalarKind::Uuid => {
+            let uuid = parse_uuid(text.as_str())?;
+            if binary {
+                Ok(SqlValue::Blob(uuid.as_bytes().to_vec()))
+            } else {
+                Ok(SqlValue::Text(uuid.hyphenated().to_string()))
+            }
+        }
+        #[cfg(feature = "rust_decimal")]
+        ScalarKind::Decimal => Ok(SqlValue::Text(parse_decimal(text.as_str())?.to_string())),
+    }
+}
+
+// Converts a value read from a column into the serde form of the field.
+pub fn from_sql(kind: ScalarKind, value: &str) -> Result<serde_json::Value> {
+    let text = match kind {
+        // Takes both the hyphenated text and the hex text of the bytes.
+        #[cfg(feature = "uuid")]
+        ScalarKind::Uuid => parse_uuid(value)?.hyphenated().to_string(),
+        #[cfg(feature = "rust_decimal")]
+        ScalarKind::Decimal => parse_decimal(value)?.to_string(),
+    };
+    Ok(serde_json::Value::String(text))
+}
+
+#[cfg(feature = "uuid")]
+fn parse_uuid(value: &str) -> Result<uuid::Uuid> {
+    uuid::Uuid::parse_str(value).map_err(|_| Error::Message(format!("invalid uuid: {}", value)))
+}
+
+#[cfg(feature = "rust_decimal")]
+fn parse_decimal(value: &str) -> Result<rust_decimal::Decimal> {
+    use std::str::FromStr;
+    rust_decimal::Decimal::from_str(value)
+        .or_else(|_| rust_decimal::Decimal::from_scientific(value))
+        .map_err(|_| Error::Message(format!("invalid decimal: {}", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "uuid")]
+    fn test_uuid() {
+        let text = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let kind = kind("Option < uuid :: Uuid >").unwrap();
+        assert_eq!(SqlValue::Blob(uuid::Uuid::parse_str(text).unwrap().as_bytes().to_vec()),
+            to_sql(kind, serde_json::Value::String(text.to_string()), true).unwrap());
+        assert_eq!(SqlValue::Text(text.to_string()), to_sql(kind, serde_json::Value::String(text.to_string()), false).unwrap());
+        assert_eq!(from_sql(kind, "67E5504410B1426F9247BB680E5FE0C8").unwrap(), text);
+        assert_eq!(from_sql(kind, text).unwrap(), text);
+        assert!(from_sql(kind, "67e55044").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "rust_decimal")]
+    fn test_decimal() {
+        let kind = kind("rust_decimal :: Decimal").unwrap();
+        assert_eq!(SqlValue::Text("12345678901234567890.10".to_string()),
+            to_sql(kind, serde_json::Value::String("12345678901234567890.10".to_string()), false).unwrap());
+        assert_eq!(from_sql(kind, "0.30").unwrap(), "0.30");
+        assert_eq!(from_sql(kind, "-7").unwrap(), "-7");
+        assert_eq!(from_sql(kind, "1.5e3").unwrap(), "1500");
+        assert!(from_sql(kind, "abc").is_err());
+    }
+}
@@ -0,0 +1,508 @@
+// Checks an entity against its live table, since rows are mapped to fields by
+// the position of their columns: `validate` reports the differences, and
+// `diff` gives the statements which bring the table in line with the entity.
+//
+//     conn.validate::<User>().await?;                // at startup
+//     for statement in conn.diff::<User>().await? {
+//         conn.query_update(statement.as_str()).exec().await?;
+//     }
+//
+// A renamed field looks like a removed column and an added one. MySQL alters
+// the columns in place, SQLite rebuilds the table, which keeps its keys,
+// unique indexes, indexes and foreign keys but no other constraint. Values of
+// columns which become NOT NULL are filled with zero or empty text.
+
+use std::fmt;
+
+use crate::{Column, ColumnInfo, ForeignKeyInfo, IndexInfo, TableDeserialize};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    MissingTable,
+    // a field without a column
+    MissingColumn(String),
+    // a column without a field
+    ExtraColumn(String),
+    // the column of the field is elsewhere in the table
+    Position { field: String, expected: usize, actual: usize },
+    Type { field: String, rust_type: String, declared_type: String },
+    // whether the column is nullable, which the field isn't or the other way round
+    Nullability { field: String, nullable: bool },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::MissingTable => write!(f, "no table"),
+            Mismatch::MissingColumn(field) => write!(f, "no column for field {}", field),
+            Mismatch::ExtraColumn(column) => write!(f, "no field for column {}", column),
+            Mismatch::Position { field, expected, actual } => {
+                write!(f, "column {} is at position {} instead of {}", field, actual, expected)
+            }
+            Mismatch::Type { field, rust_type, declared_type } => {
+                write!(f, "field {} of type {} is in a column of type {}", field, rust_type, declared_type)
+            }
+            Mismatch::Nullability { field, nullable: true } => write!(f, "column {} is nullable but its field isn't an Option", field),
+            Mismatch::Nullability { field, nullable: false } => write!(f, "column {} is NOT NULL but its field is an Option", field),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Sqlite,
+    Mysql,
+}
+
+// What a column holds, as far as it matters to read it into a field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Integer,
+    Real,
+    Text,
+    Blob,
+    // the chrono, time and rust_decimal types of fields
+    Date,
+    DateTime,
+    Decimal,
+    // anything goes, e.g. times or JSON
+    Other,
+}
+
+// The type of the field without `Option` and whitespace, and whether it had it.
+fn base_type(rust_type: &str) -> (String, bool) {
+    let ty: String = rust_type.chars().filter(|c| !c.is_whitespace()).collect();
+    match ty.strip_prefix("Option<").and_then(|t| t.strip_suffix('>')) {
+        Some(inner) => (inner.to_string(), true),
+        None => (ty, false),
+    }
+}
+
+fn field_kind(column: &Column) -> Kind {
+    if column.codec.is_some() || column.json {
+        return Kind::Other;
+    }
+    if column.binary {
+        return Kind::Blob;
+    }
+    if column.as_integer {
+        return Kind::Integer;
+    }
+    let (ty, _) = base_type(column.rust_type.as_str());
+    match ty.split('<').next().unwrap_or_default().rsplit("::").next().unwrap_or_default() {
+        "i8" | "i16" | "i32" | "i64" | "i128" | "isize" | "u8" | "u16" | "u32" | "u64" | "u128" | "usize" | "bool" => Kind::Integer,
+        "f32" | "f64" => Kind::Real,
+        "String" | "str" | "&str" | "char" | "Uuid" => Kind::Text,
+        "NaiveDate" | "Date" => Kind::Date,
+        "DateTime" | "NaiveDateTime" | "OffsetDateTime" | "PrimitiveDateTime" => Kind::DateTime,
+        "Decimal" => Kind::Decimal,
+        _ => Kind::Other,
+    }
+}
+
+// What a column of the declared type holds, also for `ormlib-codegen`.
+pub fn column_kind(dialect: Dialect, declared_type: &str) -> Kind {
+    let declared = declared_type.to_lowercase();
+    match dialect {
+        // by the rules of type affinity, with the names of dates and decimals
+        Dialect::Sqlite => {
+            if declared.contains("int") {
+                Kind::Integer
+            } else if declared.contains("char") || declared.contains("clob") || declared.contains("text") {
+                Kind::Text
+            } else if declared.is_empty() || declared.contains("blob") {
+                Kind::Blob
+            } else if declared.contains("real") || declared.contains("floa") || declared.contains("doub") {
+                Kind::Real
+            } else if declared.contains("datetime") || declared.contains("timestamp") {
+                Kind::DateTime
+            } else if declared == "date" {
+                Kind::Date
+            } else if declared.starts_with("decimal") || declared.starts_with("numeric") {
+                Kind::Decimal
+            } else {
+                Kind::Other
+            }
+        }
+        Dialect::Mysql => match declared.split(['(', ' ']).next().unwrap_or_default() {
+            "tinyint" | "smallint" | "mediumint" | "int" | "integer" | "bigint" | "bool" | "boolean" | "bit" => Kind::Integer,
+            "float" | "double" | "real" => Kind::Real,
+            "char" | "varchar" | "tinytext" | "text" | "mediumtext" | "longtext" => Kind::Text,
+            "binary" | "varbinary" | "tinyblob" | "blob" | "mediumblob" | "longblob" => Kind::Blob,
+            "datetime" | "timestamp" => Kind::DateTime,
+            "date" => Kind::Date,
+            "decimal" | "numeric" => Kind::Decimal,
+            _ => Kind::Other,
+        },
+    }
+}
+
+fn compatible(field: Kind, column: Kind) -> bool {
+    match (field, column) {
+        (Kind::Other, _) | (_, Kind::Other) => true,
+        (Kind::Real, Kind::Integer) => true,
+        // dates and decimals are text where there is no column type for them,
+        // and read as text into `String` fields
+        (Kind::Date | Kind::DateTime | Kind::Decimal, Kind::Text) => true,
+        (Kind::Text, Kind::Date | Kind::DateTime | Kind::Decimal) => true,
+        (field, column) => field == column,
+    }
+}
+
+pub(crate) fn mismatches<T: TableDeserialize>(dialect: Dialect, live: &[ColumnInfo]) -> Vec<Mismatch> {
+    if live.is_empty() {
+        return vec![Mismatch::MissingTable];
+    }
+    let fields = T::columns();
+    let mut mismatches = Vec::new();
+    for (expected, field) in fields.iter().enumerate() {
+        let Some(actual) = live.iter().position(|column| column.name == field.name) else {
+            mismatches.push(Mismatch::MissingColumn(field.name.clone()));
+            continue;
+        };
+        let column = &live[actual];
+        if actual != expected {
+            mismatches.push(Mismatch::Position { field: field.name.clone(), expected, actual });
+        }
+        if !compatible(field_kind(field), column_kind(dialect, column.declared_type.as_str())) {
+            mismatches.push(Mismatch::Type {
+                field: field.name.clone(),
+                rust_type: field.rust_type.clone(),
+                declared_type: column.declared_type.clone(),
+            });
+        }
+        let (_, optional) = base_type(field.rust_type.as_str());
+        if !column.primary_key && column.nullable != optional {
+            mismatches.push(Mismatch::Nullability { field: field.name.clone(), nullable: column.nullable });
+        }
+    }
+    for column in live.iter().filter(|column| !fields.iter().any(|field| field.name == column.name)) {
+        mismatches.push(Mismatch::ExtraColumn(column.name.clone()));
+    }
+    mismatches
+}
+
+// The column type of a field which has no column yet.
+fn column_type(dialect: Dialect, column: &Column) -> String {
+    let (ty, _) = base_type(column.rust_type.as_str());
+    let name = ty.split('<').next().unwrap_or_default().rsplit("::").next().unwrap_or_default().to_string();
+    let sqlite = |kind: Kind| match kind {
+        Kind::Integer => "INTEGER",
+        Kind::Real => "REAL",
+        Kind::Blob => "BLOB",
+        _ => "TEXT",
+    };
+    let mysql = match (field_kind(column), name.as_str()) {
+        (Kind::Blob, _) => "BINARY(16)",
+        (Kind::Integer, "i8") => "TINYINT",
+        (Kind::Integer, "u8") => "TINYINT UNSIGNED",
+        (Kind::Integer, "bool") => "TINYINT(1)",
+        (Kind::Integer, "i16") => "SMALLINT",
+        (Kind::Integer, "u16") => "SMALLINT UNSIGNED",
+        (Kind::Integer, "i32") => "INT",
+        (Kind::Integer, "u32") => "INT UNSIGNED",
+        (Kind::Integer, "u64" | "u128" | "usize") => "BIGINT UNSIGNED",
+        (Kind::Integer, _) => "BIGINT",
+        (Kind::Real, "f32") => "FLOAT",
+        (Kind::Real, _) => "DOUBLE",
+        (Kind::Text, "Uuid") => "CHAR(36)",
+        (Kind::Text, _) => "TEXT",
+        (Kind::DateTime, _) => "DATETIME(6)",
+        (Kind::Date, _) => "DATE",
+        (Kind::Decimal, _) => "DECIMAL(38,10)",
+        (Kind::Other, _) if column.json => "JSON",
+        (Kind::Other, "NaiveTime" | "Time") => "TIME(6)",
+        _ => "TEXT",
+    };
+    match dialect {
+        Dialect::Sqlite => sqlite(field_kind(column)).to_string(),
+        Dialect::Mysql => mysql.to_string(),
+    }
+}
+
+// The value of the rows of a column which becomes NOT NULL.
+fn zero(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Integer | Kind::Real => "0",
+        Kind::Blob => "X''",
+        _ => "''",
+    }
+}
+
+fn is_optional(column: &Column) -> bool {
+    base_type(column.rust_type.as_str()).1
+}
+
+// The definition of the column of a field, from the live column when it fits
+// the field.
+fn definition(dialect: Dialect, quote: &impl Fn(&str) -> String, field: &Column, live: Option<&ColumnInfo>, keys: usize) -> String {
+    let fits = live.filter(|column| compatible(field_kind(field), column_kind(dialect, column.declared_type.as_str())));
+    let column_type = match fits {
+        Some(column) => column.declared_type.clone(),
+        None => column_type(dialect, field),
+    };
+    let mut definition = format!("{} {}", quote(field.name.as_str()), column_type);
+    let primary_key = live.map(|column| column.primary_key).unwrap_or(field.name == "id");
+    let auto_increment = live.map(|column| column.auto_increment).unwrap_or(field.generated);
+    if primary_key && keys <= 1 {
+        definition.push_str(" PRIMARY KEY");
+        if auto_increment {
+            definition.push_str(match dialect {
+                Dialect::Sqlite => " AUTOINCREMENT",
+                Dialect::Mysql => " AUTO_INCREMENT",
+            });
+        }
+    } else if !is_optional(field) || primary_key {
+        definition.push_str(" NOT NULL");
+    }
+    if let Some(default) = fits.and_then(|column| column.default.as_ref()) {
+        definition.push_str(format!(" DEFAULT {}", default_sql(dialect, default)).as_str());
+    }
+    definition
+}
+
+// MySQL has the defaults of text columns without quotes.
+fn default_sql(dialect: Dialect, default: &str) -> String {
+    let upper = default.to_uppercase();
+    let raw = dialect == Dialect::Sqlite || default.parse::<f64>().is_ok() || upper == "NULL" || upper.starts_with("CURRENT_");
+    match raw {
+        true => default.to_string(),
+        false => format!("'{}'", default.replace('\\', "\\\\").replace('\'', "''")),
+    }
+}
+
+pub(crate) fn create_table<T: TableDeserialize>(dialect: Dialect, quote: impl Fn(&str) -> String, table: &str) -> String {
+    let definitions: Vec<String> = T::columns().iter().map(|field| definition(dialect, &quote, field, None, 1)).collect();
+    format!("CREATE TABLE {} ({})", table, definitions.join(", "))
+}
+
+// MySQL alters the columns in place: drops the extra ones, then adds or moves
+// each field to its position.
+pub(crate) fn mysql_alter<T: TableDeserialize>(quote: impl Fn(&str) -> String, table: &str, live: &[ColumnInfo]) -> Vec<String> {
+    let fields = T::columns();
+    let mut statements = Vec::new();
+    let mut order: Vec<String> = Vec::new();
+    for column in live {
+        match fields.iter().any(|field| field.name == column.name) {
+            true => order.push(column.name.clone()),
+            false => statements.push(format!("ALTER TABLE {} DROP COLUMN {}", table, quote(column.name.as_str()))),
+        }
+    }
+    let keys = live.iter().filter(|column| column.primary_key).count();
+    for (i, field) in fields.iter().enumerate() {
+        let place = match i {
+            0 => " FIRST".to_string(),
+            _ => format!(" AFTER {}", quote(fields[i - 1].name.as_str())),
+        };
+        let column = live.iter().find(|column| column.name == field.name);
+        let definition = definition(Dialect::Mysql, &quote, field, column, keys);
+        match column {
+            None => {
+                order.insert(i, field.name.clone());
+                statements.push(format!("ALTER TABLE {} ADD COLUMN {}{}", table, definition, place));
+            }
+            Some(column) => {
+                let kind = field_kind(field);
+                let fits = compatible(kind, column_kind(Dialect::Mysql, column.declared_type.as_str()));
+                let nullability = !column.primary_key && column.nullable != is_optional(field);
+                let position = order.iter().position(|name| *name == field.name);
+                if !fits || nullability || position != Some(i) {
+                    // the key stays as it is
+                    let definition = definition.replace(" PRIMARY KEY", "");
+                    if column.nullable && !is_optional(field) && !column.primary_key {
+                        statements.push(format!("UPDATE {} SET {} = {} WHERE {} IS NULL", table, quote(field.name.as_str()),
+                            zero(kind), quote(field.name.as_str())));
+                    }
+                    order.retain(|name| *name != field.name);
+                    order.insert(i, field.name.clone());
+                    statements.push(format!("ALTER TABLE {} MODIFY COLUMN {}{}", table, definition, place));
+                }
+            }
+        }
+    }
+    statements
+}
+
+// What SQLite keeps of a table when it rebuilds it, besides its columns.
+pub(crate) struct SqliteTable {
+    pub autoincrement: bool,
+    pub indexes: Vec<IndexInfo>,
+    // the SQL of each index which has one, by name
+    pub index_sql: Vec<(String, String)>,
+    pub foreign_keys: Vec<ForeignKeyInfo>,
+}
+
+// SQLite can't alter columns, so the table is copied into a new one. In an
+// attached database `schema` the indexes are created again from their columns,
+// as their SQL names the table without it.
+pub(crate) fn sqlite_rebuild<T: TableDeserialize>(quote: impl Fn(&str) -> String, schema: Option<&str>, name: &str, live: &[ColumnInfo], table: SqliteTable) -> Vec<String> {
+    let qualify = |table: &str| match schema {
+        Some(schema) => format!("{}.{}", quote(schema), quote(table)),
+        None => quote(table),
+    };
+    let fields = T::columns();
+    let exists = |column: &String| fields.iter().any(|field| field.name == *column);
+    let keys: Vec<&ColumnInfo> = live.iter().filter(|column| column.primary_key && exists(&column.name)).collect();
+    let mut definitions: Vec<String> = fields.iter().map(|field| {
+        let column = live.iter().find(|column| column.name == field.name);
+        let definition = definition(Dialect::Sqlite, &quote, field, column, keys.len());
+        match table.autoincrement {
+            true => definition,
+            false => definition.replace(" AUTOINCREMENT", ""),
+        }
+    }).collect();
+    if keys.len() > 1 {
+        let columns: Vec<String> = keys.iter().map(|column| quote(column.name.as_str())).collect();
+        definitions.push(format!("PRIMARY KEY ({})", columns.join(", ")));
+    }
+    for key in table.foreign_keys.iter().filter(|key| key.columns.iter().all(exists)) {
+        let columns: Vec<String> = key.columns.iter().map(|column| quote(column.as_str())).collect();
+        let referenced = match key.referenced_columns.iter().all(|column| column.is_empty()) {
+            true => String::new(),
+            false => format!(" ({})", key.referenced_columns.iter().map(|column| quote(column.as_str())).collect::<Vec<_>>().join(", ")),
+        };
+        definitions.push(format!("FOREIGN KEY ({}) REFERENCES {}{} ON UPDATE {} ON DELETE {}",
+            columns.join(", "), quote(key.referenced_table.as_str()), referenced, key.on_update, key.on_delete));
+    }
+
+    let rebuilt = format!("{}_ormlib_rebuild", name);
+    let (mut targets, mut values) = (Vec::new(), Vec::new());
+    for field in fields.iter() {
+        let column = quote(field.name.as_str());
+        let value = match live.iter().find(|live| live.name == field.name) {
+            Some(live) if live.nullable && !is_optional(field) && !live.primary_key => {
+                format!("coalesce({}, {})", column, zero(field_kind(field)))
+            }
+            Some(_) => column.clone(),
+            None if is_optional(field) || field.name == "id" => continue,
+            None => zero(field_kind(field)).to_string(),
+        };
+        targets.push(column);
+        values.push(value);
+    }
+    let mut statements = vec![
+        format!("CREATE TABLE {} ({})", qualify(rebuilt.as_str()), definitions.join(", ")),
+        format!("INSERT INTO {} ({}) SELECT {} FROM {}", qualify(rebuilt.as_str()), targets.join(", "), values.join(", "), qualify(name)),
+        format!("DROP TABLE {}", qualify(name)),
+        format!("ALTER TABLE {} RENAME TO {}", qualify(rebuilt.as_str()), quote(name)),
+    ];
+    let key_names: Vec<String> = keys.iter().map(|column| column.name.clone()).collect();
+    for index in table.indexes.iter().filter(|index| index.columns.iter().all(exists)) {
+        let columns: Vec<String> = index.columns.iter().map(|column| quote(column.as_str())).collect();
+        let unique = if index.unique { "UNIQUE " } else { "" };
+        match table.index_sql.iter().find(|(index_name, _)| *index_name == index.name) {
+            Some((_, sql)) if schema.is_none() => statements.push(sql.clone()),
+            Some(_) => statements.push(format!("CREATE {}INDEX {} ON {} ({})", unique, qualify(index.name.as_str()), quote(name), columns.join(", "))),
+            // of a UNIQUE constraint, other than the one of the key
+            None if index.unique && index.columns != key_names => statements.push(format!("CREATE UNIQUE INDEX {} ON {} ({})",
+                qualify(format!("{}_{}_unique", name, index.columns.join("_")).as_str()), quote(name), columns.join(", "))),
+            None => {}
+        }
+    }
+    statements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct User;
+
+    impl TableDeserialize for User {
+        fn same_name() -> String {
+            "user".to_string()
+        }
+        fn columns() -> Vec<Column> {
+            let column = |name: &str, rust_type: &str| {
+                let mut column = Column::new(name);
+                column.rust_type = rust_type.to_string();
+                column
+            };
+            vec![column("id", "i32"), column("name", "String"), column("age", "Option < i32 >")]
+        }
+    }
+
+    fn live(name: &str, declared_type: &str, nullable: bool) -> ColumnInfo {
+        ColumnInfo {
+            name: name.to_string(),
+            declared_type: declared_type.to_string(),
+            nullable,
+            default: None,
+            primary_key: name == "id",
+            auto_increment: name == "id",
+        }
+    }
+
+    fn quote(name: &str) -> String {
+        format!("`{}`", name)
+    }
+
+    #[test]
+    fn test_mismatches() {
+        assert_eq!(vec![Mismatch::MissingTable], mismatches::<User>(Dialect::Sqlite, &[]));
+        let columns = vec![live("id", "INTEGER", false), live("name", "TEXT", false), live("age", "INTEGER", true)];
+        assert!(mismatches::<User>(Dialect::Sqlite, &columns).is_empty());
+        let columns = vec![live("id", "int", false), live("age", "varchar(10)", false), live("note", "text", true)];
+        assert_eq!(vec![
+            Mismatch::MissingColumn("name".to_string()),
+            Mismatch::Position { field: "age".to_string(), expected: 2, actual: 1 },
+            Mismatch::Type { field: "age".to_string(), rust_type: "Option < i32 >".to_string(), declared_type: "varchar(10)".to_string() },
+            Mismatch::Nullability { field: "age".to_string(), nullable: false },
+            Mismatch::ExtraColumn("note".to_string()),
+        ], mismatches::<User>(Dialect::Mysql, &columns));
+    }
+
+    #[test]
+    fn test_column_kind() {
+        assert_eq!(Kind::Real, column_kind(Dialect::Sqlite, "DOUBLE PRECISION"));
+        assert_eq!(Kind::DateTime, column_kind(Dialect::Sqlite, "DATETIME"));
+        assert_eq!(Kind::Date, column_kind(Dialect::Mysql, "date"));
+        assert_eq!(Kind::Decimal, column_kind(Dialect::Mysql, "decimal(10,2)"));
+        assert_eq!(Kind::Other, column_kind(Dialect::Mysql, "time(6)"));
+        // a string field may hold a date-time, an integer one may not
+        let columns = vec![live("id", "INTEGER", false), live("name", "DATETIME", false), live("age", "INTEGER", true)];
+        assert!(mismatches::<User>(Dialect::Sqlite, &columns).is_empty());
+        let columns = vec![live("id", "int", false), live("name", "text", false), live("age", "datetime", true)];
+        assert!(matches!(mismatches::<User>(Dialect::Mysql, &columns)[..], [Mismatch::Type { .. }]));
+        let field = |rust_type: &str| {
+            let mut column = Column::new("at");
+            column.rust_type = rust_type.to_string();
+            field_kind(&column)
+        };
+        assert_eq!(Kind::DateTime, field("Option<chrono::DateTime<chrono::Utc>>"));
+        assert_eq!(Kind::Date, field("time::Date"));
+        assert!(compatible(field("rust_decimal::Decimal"), column_kind(Dialect::Mysql, "decimal(10,2)")));
+        assert!(compatible(field("NaiveDateTime"), column_kind(Dialect::Sqlite, "TEXT")));
+        assert!(!compatible(field("NaiveDate"), column_kind(Dialect::Mysql, "datetime")));
+        assert!(!compatible(field("f64"), column_kind(Dialect::Mysql, "decimal(10,2)")));
+    }
+
+    #[test]
+    fn test_mysql_alter() {
+        assert_eq!("CREATE TABLE `user` (`id` INT PRIMARY KEY AUTO_INCREMENT, `name` TEXT NOT NULL, `age` INT)",
+            create_table::<User>(Dialect::Mysql, quote, "`user`"));
+        let columns = vec![live("id", "int", false), live("age", "int", true), live("name", "varchar(20)", true), live("note", "text", true)];
+        assert_eq!(vec![
+            "ALTER TABLE `user` DROP COLUMN `note`",
+            "UPDATE `user` SET `name` = '' WHERE `name` IS NULL",
+            "ALTER TABLE `user` MODIFY COLUMN `name` varchar(20) NOT NULL AFTER `id`",
+        ], mysql_alter::<User>(quote, "`user`", &columns));
+    }
+
+    #[test]
+    fn test_sqlite_rebuild() {
+        let columns = vec![live("id", "INTEGER", false), live("name", "TEXT", true), live("note", "TEXT", true)];
+        let table = SqliteTable {
+            autoincrement: false,
+            indexes: vec![IndexInfo { name: "user_note".to_string(), columns: vec!["note".to_string()], unique: false }],
+            index_sql: vec![("user_note".to_string(), "CREATE INDEX user_note ON user (note)".to_string())],
+            foreign_keys: Vec::new(),
+        };
+        assert_eq!(vec![
+            "CREATE TABLE `user_ormlib_rebuild` (`id` INTEGER PRIMARY KEY, `name` TEXT NOT NULL, `age` INTEGER)",
+            "INSERT INTO `user_ormlib_rebuild` (`id`, `name`) SELECT `id`, coalesce(`name`, '') FROM `user`",
+            "DROP TABLE `user`",
+            "ALTER TABLE `user_ormlib_rebuild` RENAME TO `user`",
+        ], sqlite_rebuild::<User>(quote, None, "user", &columns, table));
+    }
+}
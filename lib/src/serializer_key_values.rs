@@ -7,29 +7,43 @@
 // except according to those terms.
 
 use crate::serializer_error::{Error, Result};
+use crate::serializer_values::field_value;
 use serde::ser::{self, Serialize};
-use crate::ORMTrait;
+use std::time::SystemTime;
+use crate::{Column, ORMTrait, SqlValue};
 use crate::sqlite::ORM;
 
 pub struct Serializer {
     // This string starts empty and JSON is appended as values are serialized.
     output: String,
+    // Quotes column names with the identifier quotes of the target dialect.
+    quote: fn(&str) -> String,
+    columns: Vec<Column>,
+    // The time of the `created_at` and `updated_at` columns.
+    now: SystemTime,
+    // The fields to update, all when `None`; the version and `updated_at`
+    // columns are always updated.
+    only: Option<Vec<String>>,
+    // The values of the placeholders of the fields, in order.
+    params: Vec<SqlValue>,
 }
 
-// By convention, the public API of a Serde serializer is one or more `to_abc`
-// functions such as `to_string`, `to_bytes`, or `to_writer` depending on what
-// Rust types the serializer is able to produce as output.
-//
-// This basic serializer supports only `to_string`.
-pub fn to_string<T>(value: &T) -> Result<String>
+// The `("k" = ?,...)` assignments of an update, with the values of their
+// placeholders.
+pub fn to_placeholders<T>(value: &T, quote: fn(&str) -> String, columns: Vec<Column>, now: SystemTime, only: Option<Vec<String>>) -> Result<(String, Vec<SqlValue>)>
     where
         T: Serialize,
 {
     let mut serializer = Serializer {
         output: String::new(),
+        quote,
+        columns,
+        now,
+        only,
+        params: Vec::new(),
     };
     value.serialize(&mut serializer)?;
-    Ok(serializer.output)
+    Ok((serializer.output, serializer.params))
 }
 
 impl<'a> ser::Serializer for &'a mut Serializer {
@@ -175,7 +189,8 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     // When serializing a unit variant (or any other kind of variant), formats
     // can choose whether to keep track of it by index or by name. Binary
     // formats typically use the index of the variant and human-readable formats
-    // typically use the name.
+    // typically use the name. Fields go through `field_value`, which keeps the
+    // index for `#[column(as_integer)]` columns.
     fn serialize_unit_variant(
         self,
         _name: &'static str,
@@ -462,14 +477,29 @@ impl<'a> ser::SerializeStruct for &'a mut Serializer {
         where
             T: ?Sized + Serialize,
     {
+        let column = self.columns.iter().find(|column| column.name == key).cloned();
+        // The creation time is kept as it was inserted.
+        if column.as_ref().is_some_and(|column| column.created_at) {
+            return Ok(());
+        }
+        let bookkeeping = column.as_ref().is_some_and(|column| column.version || column.updated_at);
+        if self.only.as_ref().is_some_and(|only| !bookkeeping && !only.iter().any(|field| field == key)) {
+            return Ok(());
+        }
         if key != "id" {
             if !self.output.ends_with('(') {
                 self.output += ",";
             }
-            self.output += key;
+            self.output += (self.quote)(key).as_str();
 
             self.output += " = ";
-            _ = value.serialize(&mut **self);
+            if column.as_ref().is_some_and(|column| column.version) {
+                self.output += format!("{} + 1", (self.quote)(key)).as_str();
+                return Ok(());
+            }
+            let value = field_value(column.as_ref(), value, self.now)?;
+            self.params.push(value);
+            self.output += "?";
         }
         Ok(())
     }
@@ -508,8 +538,11 @@ impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
 
 #[cfg(test)]
 mod tests {
-    use super::to_string;
+    use super::to_placeholders;
+    use std::time::UNIX_EPOCH;
     use serde_derive::Serialize;
+    use crate::{ORMTrait, SqlValue};
+    use crate::sqlite::ORM;
 
     #[test]
     fn test_enum() {
@@ -530,6 +563,9 @@ mod tests {
             age: -1,
         };
 
-        println!("{}", to_string(&user).unwrap())
+        assert_eq!(
+            (r#"("name" = ?,"age" = ?)"#.to_string(), vec![SqlValue::Text("test".to_string()), SqlValue::Integer(-1)]),
+            to_placeholders(&user, ORM::quote, Vec::new(), UNIX_EPOCH, None).unwrap()
+        );
     }
 }
@@ -8,10 +8,15 @@
 
 use crate::serializer_error::{Error, Result};
 use serde::ser::{self, Serialize};
+use crate::Column;
+use crate::serializer_values::is_generated;
 
 pub struct Serializer {
     // This string starts empty and JSON is appended as values are serialized.
     output: String,
+    // Quotes column names with the identifier quotes of the target dialect.
+    quote: fn(&str) -> String,
+    columns: Vec<Column>,
 }
 
 // By convention, the public API of a Serde serializer is one or more `to_abc`
@@ -19,12 +24,14 @@ pub struct Serializer {
 // Rust types the serializer is able to produce as output.
 //
 // This basic serializer supports only `to_string`.
-pub fn to_string<T>(value: &T) -> Result<String>
+pub fn to_string<T>(value: &T, quote: fn(&str) -> String, columns: Vec<Column>) -> Result<String>
     where
         T: Serialize,
 {
     let mut serializer = Serializer {
         output: String::new(),
+        quote,
+        columns,
     };
     value.serialize(&mut serializer)?;
     Ok(serializer.output)
@@ -460,11 +467,11 @@ impl<'a> ser::SerializeStruct for &'a mut Serializer {
         where
             T: ?Sized + Serialize,
     {
-        if key != "id" {
+        if !is_generated(&self.columns, key) {
             if !self.output.ends_with('(') {
                 self.output += ",";
             }
-            self.output += key;
+            self.output += (self.quote)(key).as_str();
 
         }
         Ok(())
@@ -506,6 +513,8 @@ impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
 mod tests {
     use super::to_string;
     use serde_derive::Serialize;
+    use crate::ORMTrait;
+    use crate::sqlite::ORM;
 
     #[test]
     fn test_enum() {
@@ -526,6 +535,6 @@ mod tests {
             age: 0,
         };
 
-        println!("{}", to_string(&user).unwrap())
+        println!("{}", to_string(&user, ORM::quote, Vec::new()).unwrap())
     }
 }
@@ -8,29 +8,282 @@
 
 use crate::serializer_error::{Error, Result};
 use serde::ser::{self, Serialize};
-use crate::ORMTrait;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::{Column, ORMTrait, SqlValue, to_hex};
 use crate::sqlite::ORM;
 
 
 pub struct Serializer {
     // This string starts empty and JSON is appended as values are serialized.
     output: String,
+    columns: Vec<Column>,
+    // The time of the `created_at` and `updated_at` columns.
+    now: SystemTime,
+    // The values of the placeholders of the fields, in order.
+    params: Vec<SqlValue>,
 }
 
-// By convention, the public API of a Serde serializer is one or more `to_abc`
-// functions such as `to_string`, `to_bytes`, or `to_writer` depending on what
-// Rust types the serializer is able to produce as output.
-//
-// This basic serializer supports only `to_string`.
-pub fn to_string<T>(value: &T) -> Result<String>
+// The `(?,?)` values of an insert, with the values of its placeholders.
+pub fn to_placeholders<T>(value: &T, columns: Vec<Column>, now: SystemTime) -> Result<(String, Vec<SqlValue>)>
     where
         T: Serialize,
 {
     let mut serializer = Serializer {
         output: String::new(),
+        columns,
+        now,
+        params: Vec::new(),
     };
     value.serialize(&mut serializer)?;
-    Ok(serializer.output)
+    Ok((serializer.output, serializer.params))
+}
+
+// Some fields need more than their serde form to be written: fields with a
+// codec are converted by it, date-times, UUIDs and decimals follow the storage
+// of their column and compound values become JSON text.
+// `None` means the field is written as usual.
+pub fn to_value<T>(column: Option<&Column>, value: &T) -> Result<Option<SqlValue>>
+    where
+        T: ?Sized + Serialize,
+{
+    if let Some(codec) = column.and_then(|column| column.codec) {
+        let value = (codec.to_sql)(serde_json::to_value(value)?).map_err(|e| Error::Message(e.to_string()))?;
+        return Ok(Some(match value {
+            SqlValue::Real(real) if !real.is_finite() => SqlValue::Null,
+            value => value,
+        }));
+    }
+    let json = column.is_some_and(|column| column.json);
+    #[cfg(any(feature = "chrono", feature = "time"))]
+    if let Some(column) = column {
+        if let Some(kind) = crate::datetime::kind(column.rust_type.as_str()) {
+            return crate::datetime::to_sql(kind, serde_json::to_value(value)?, column.as_integer).map(Some);
+        }
+    }
+    #[cfg(any(feature = "uuid", feature = "rust_decimal"))]
+    if let Some(column) = column {
+        if let Some(kind) = crate::scalar::kind(column.rust_type.as_str()) {
+            return crate::scalar::to_sql(kind, serde_json::to_value(value)?, column.binary).map(Some);
+        }
+    }
+    Ok(to_json(value, json).map(SqlValue::Text))
+}
+
+// Fields filled in from the clock instead of the entity.
+pub fn timestamp_value(column: &Column, now: SystemTime) -> SqlValue {
+    match column.as_integer {
+        true => SqlValue::Integer(crate::clock::unix(now).0),
+        false => SqlValue::Text(crate::clock::timestamp(now, false)),
+    }
+}
+
+// The value of a field to bind to its placeholder.
+pub fn field_value<T>(column: Option<&Column>, value: &T, now: SystemTime) -> Result<SqlValue>
+    where
+        T: ?Sized + Serialize,
+{
+    if let Some(column) = column.filter(|column| column.created_at || column.updated_at) {
+        return Ok(timestamp_value(column, now));
+    }
+    match to_value(column, value)? {
+        Some(value) => Ok(value),
+        None => value.serialize(ValueSerializer { as_integer: column.is_some_and(|column| column.as_integer) }),
+    }
+}
+
+// The `id` is left out of inserts when the database generates it.
+pub fn is_generated(columns: &[Column], key: &str) -> bool {
+    match columns.iter().find(|column| column.name == key) {
+        Some(column) => column.generated,
+        None => key == "id",
+    }
+}
+
+// The value of a field of an entity, such as the `id` to find it by its key.
+pub fn field_to_value<T>(value: &T, columns: Vec<Column>, key: &str) -> Result<SqlValue>
+    where
+        T: Serialize,
+{
+    let field = serde_json::to_value(value)?.get(key).cloned().unwrap_or_default();
+    field_value(columns.iter().find(|column| column.name == key), &field, UNIX_EPOCH)
+}
+
+// The SQL literal of a field of an entity.
+pub fn field_to_string<T>(value: &T, columns: Vec<Column>, key: &str) -> Result<String>
+    where
+        T: Serialize,
+{
+    let literal = match field_to_value(value, columns, key)? {
+        SqlValue::Null => "null".to_string(),
+        SqlValue::Integer(int) => int.to_string(),
+        SqlValue::Real(real) => format!("{:?}", real),
+        SqlValue::Text(text) => format!("\"{}\"", ORM::escape(text.as_str())),
+        SqlValue::Blob(bytes) => format!("X'{}'", to_hex(&bytes)),
+    };
+    Ok(literal)
+}
+
+// Compound values such as nested structs, sequences, maps and data-carrying
+// enum variants have no SQL literal form, so they are stored as JSON text
+// instead. `#[column(json)]` fields are stored as JSON text whatever they hold,
+// except for `None` which stays a SQL `null`.
+pub fn to_json<T>(value: &T, force: bool) -> Option<String>
+    where
+        T: ?Sized + Serialize,
+{
+    match serde_json::to_value(value) {
+        Ok(json) if json.is_object() || json.is_array() => Some(json.to_string()),
+        Ok(json) if force && !json.is_null() => Some(json.to_string()),
+        _ => None,
+    }
+}
+
+// The value of a field which serializes to a primitive. Unit variants are
+// their name, or their index in `#[column(as_integer)]` fields. Compound
+// values are JSON text, see `to_json`, before they get here.
+struct ValueSerializer {
+    as_integer: bool,
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = SqlValue;
+    type Error = Error;
+
+    type SerializeSeq = ser::Impossible<SqlValue, Error>;
+    type SerializeTuple = ser::Impossible<SqlValue, Error>;
+    type SerializeTupleStruct = ser::Impossible<SqlValue, Error>;
+    type SerializeTupleVariant = ser::Impossible<SqlValue, Error>;
+    type SerializeMap = ser::Impossible<SqlValue, Error>;
+    type SerializeStruct = ser::Impossible<SqlValue, Error>;
+    type SerializeStructVariant = ser::Impossible<SqlValue, Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<SqlValue> {
+        Ok(SqlValue::Integer(i64::from(v)))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<SqlValue> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<SqlValue> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<SqlValue> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<SqlValue> {
+        Ok(SqlValue::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<SqlValue> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<SqlValue> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<SqlValue> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    // Beyond `i64` as its digits, which MySQL BIGINT UNSIGNED columns take.
+    fn serialize_u64(self, v: u64) -> Result<SqlValue> {
+        match i64::try_from(v) {
+            Ok(v) => Ok(SqlValue::Integer(v)),
+            Err(_) => Ok(SqlValue::Text(v.to_string())),
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<SqlValue> {
+        self.serialize_f64(f64::from(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<SqlValue> {
+        Ok(SqlValue::Real(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<SqlValue> {
+        Ok(SqlValue::Text(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<SqlValue> {
+        Ok(SqlValue::Text(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<SqlValue> {
+        Ok(SqlValue::Blob(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<SqlValue> {
+        Ok(SqlValue::Null)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<SqlValue>
+        where
+            T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<SqlValue> {
+        Ok(SqlValue::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<SqlValue> {
+        Ok(SqlValue::Null)
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, variant_index: u32, variant: &'static str) -> Result<SqlValue> {
+        match self.as_integer {
+            true => Ok(SqlValue::Integer(i64::from(variant_index))),
+            false => Ok(SqlValue::Text(variant.to_string())),
+        }
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<SqlValue>
+        where
+            T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(self, _name: &'static str, _variant_index: u32, variant: &'static str, _value: &T) -> Result<SqlValue>
+        where
+            T: ?Sized + Serialize,
+    {
+        Err(Error::Message(format!("no column value for variant {}", variant)))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(Error::Message("no column value for a sequence".to_string()))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(Error::Message("no column value for a tuple".to_string()))
+    }
+
+    fn serialize_tuple_struct(self, name: &'static str, _len: usize) -> Result<Self::SerializeTupleStruct> {
+        Err(Error::Message(format!("no column value for {}", name)))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str, _len: usize) -> Result<Self::SerializeTupleVariant> {
+        Err(Error::Message(format!("no column value for variant {}", variant)))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(Error::Message("no column value for a map".to_string()))
+    }
+
+    fn serialize_struct(self, name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(Error::Message(format!("no column value for {}", name)))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str, _len: usize) -> Result<Self::SerializeStructVariant> {
+        Err(Error::Message(format!("no column value for variant {}", variant)))
+    }
 }
 
 impl<'a> ser::Serializer for &'a mut Serializer {
@@ -176,7 +429,8 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     // When serializing a unit variant (or any other kind of variant), formats
     // can choose whether to keep track of it by index or by name. Binary
     // formats typically use the index of the variant and human-readable formats
-    // typically use the name.
+    // typically use the name. Fields go through `field_value`, which keeps the
+    // index for `#[column(as_integer)]` columns.
     fn serialize_unit_variant(
         self,
         _name: &'static str,
@@ -464,13 +718,14 @@ impl<'a> ser::SerializeStruct for &'a mut Serializer {
             T: ?Sized + Serialize,
     {
 
-        if key != "id" {
+        if !is_generated(&self.columns, key) {
             if !self.output.ends_with('(') {
                 self.output += ",";
             }
-            // key.serialize(&mut **self)?;
-            // self.output += "::";
-            _ = value.serialize(&mut **self);
+            let column = self.columns.iter().find(|column| column.name == key);
+            let value = field_value(column, value, self.now)?;
+            self.params.push(value);
+            self.output += "?";
         }
         Ok(())
     }
@@ -509,7 +764,9 @@ impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
 
 #[cfg(test)]
 mod tests {
-    use super::to_string;
+    use super::to_placeholders;
+    use std::time::UNIX_EPOCH;
+    use crate::{Column, SqlValue};
     use serde_derive::Serialize;
 
     #[test]
@@ -531,6 +788,90 @@ mod tests {
         };
 
 
-        println!("{}", to_string(&user).unwrap())
+        assert_eq!(
+            ("(?,?)".to_string(), vec![SqlValue::Text("test".to_string()), SqlValue::Integer(18)]),
+            to_placeholders(&user, Vec::new(), UNIX_EPOCH).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_variants() {
+        #[derive(Serialize)]
+        pub enum Status {
+            Active,
+            Blocked,
+        }
+
+        #[derive(Serialize)]
+        pub enum Shape {
+            Circle(u32),
+            Rect { w: u32, h: u32 },
+        }
+
+        #[derive(Serialize)]
+        pub struct Item {
+            id: u32,
+            status: Status,
+            level: Status,
+            shape: Shape,
+            other: Option<Shape>,
+        }
+        let item = Item {
+            id: 1,
+            status: Status::Blocked,
+            level: Status::Active,
+            shape: Shape::Circle(5),
+            other: Some(Shape::Rect { w: 1, h: 2 }),
+        };
+        let mut level = Column::new("level");
+        level.as_integer = true;
+
+        assert_eq!(
+            ("(?,?,?,?)".to_string(), vec![
+                SqlValue::Text("Blocked".to_string()),
+                SqlValue::Integer(0),
+                SqlValue::Text(r#"{"Circle":5}"#.to_string()),
+                SqlValue::Text(r#"{"Rect":{"h":2,"w":1}}"#.to_string()),
+            ]),
+            to_placeholders(&item, vec![level], UNIX_EPOCH).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        #[derive(Serialize)]
+        pub struct Address {
+            city: String,
+        }
+
+        #[derive(Serialize)]
+        pub struct Item {
+            id: u32,
+            tags: Vec<String>,
+            address: Address,
+            note: String,
+            extra: Option<String>,
+        }
+        let item = Item {
+            id: 1,
+            tags: vec!["a".to_string(), "b".to_string()],
+            address: Address { city: "Oslo".to_string() },
+            note: "plain".to_string(),
+            extra: None,
+        };
+        let mut note = Column::new("note");
+        note.json = true;
+        let mut extra = Column::new("extra");
+        extra.json = true;
+
+        assert_eq!(
+            ("(?,?,?,?)".to_string(), vec![
+                SqlValue::Text(r#"["a","b"]"#.to_string()),
+                SqlValue::Text(r#"{"city":"Oslo"}"#.to_string()),
+                SqlValue::Text(r#""plain""#.to_string()),
+                SqlValue::Null,
+            ]),
+            to_placeholders(&item, vec![note, extra], UNIX_EPOCH).unwrap()
+        );
     }
 }
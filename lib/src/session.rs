@@ -0,0 +1,160 @@
+// A unit of work over an ORM: entities loaded through the session are kept
+// by table and key, so loading one again doesn't query the database, and
+// writes are queued until `flush` runs them all in one transaction.
+//
+//     let mut session = conn.session();
+//     let user: Option<User> = session.find_one(1).await?;
+//     session.add(Order { id: 0, user_id: 1 });
+//     session.modify(User { age: 31, ..user.unwrap() });
+//     session.flush().await?;
+//
+// `flush` orders the writes by the foreign keys between their tables: the
+// inserts of the referenced tables first, then the updates as they were
+// queued, then the removals of the referencing tables first. Within a table,
+// inserts run as they were queued and removals in reverse. An update still
+// can't point a row at one which is removed in the same flush, nor are the
+// rows of a table which references itself ordered: flush in between then.
+//
+// The flush holds the connection, as `transaction` does, and within a
+// transaction it is a savepoint.
+
+use std::any::Any;
+use std::collections::HashMap;
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+
+use crate::{ORMError, ORMTrait, serializer_values, TableDeserialize};
+
+pub struct Session<'a, O: ORMTrait<O>> {
+    pub(crate) orm: &'a O,
+    // by table and the literal of the key
+    identity: HashMap<(String, String), Box<dyn Any + Send>>,
+    pending: Vec<Pending<'a>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Order {
+    Insert,
+    Update,
+    Delete,
+}
+
+pub(crate) struct Pending<'a> {
+    order: Order,
+    table: String,
+    run: Box<dyn FnOnce() -> BoxFuture<'a, Result<(), ORMError>> + Send + 'a>,
+}
+
+impl<'a, O: ORMTrait<O>> Session<'a, O> {
+    pub fn new(orm: &'a O) -> Self {
+        Session {
+            orm,
+            identity: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    // The number of queued writes.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Forgets the loaded entities, so that they are read again.
+    pub fn clear(&mut self) {
+        self.identity.clear();
+    }
+
+    fn key<T: TableDeserialize>(id: String) -> (String, String) {
+        (O::quote_table(T::same_schema(), T::same_name().as_str()), id)
+    }
+
+    pub(crate) fn id_of<T: TableDeserialize + Serialize>(entity: &T) -> Result<String, ORMError> {
+        serializer_values::field_to_string(entity, T::columns(), "id").map_err(|e| ORMError::InvalidValue(e.to_string()))
+    }
+
+    pub(crate) fn cached<T: TableDeserialize + Clone + 'static>(&self, id: String) -> Option<T> {
+        self.identity.get(&Self::key::<T>(id)).and_then(|entity| entity.downcast_ref::<T>()).cloned()
+    }
+
+    pub(crate) fn cache<T: TableDeserialize + Send + 'static>(&mut self, id: String, entity: T) {
+        self.identity.insert(Self::key::<T>(id), Box::new(entity));
+    }
+
+    pub(crate) fn evict<T: TableDeserialize>(&mut self, id: String) {
+        self.identity.remove(&Self::key::<T>(id));
+    }
+
+    pub(crate) fn enqueue(&mut self, order: Order, table: String, run: impl FnOnce() -> BoxFuture<'a, Result<(), ORMError>> + Send + 'a) {
+        self.pending.push(Pending { order, table, run: Box::new(run) });
+    }
+
+    // The tables of the queued writes, once each.
+    pub(crate) fn pending_tables(&self) -> Vec<String> {
+        let mut tables: Vec<String> = self.pending.iter().map(|p| p.table.clone()).collect();
+        tables.sort();
+        tables.dedup();
+        tables
+    }
+
+    // The queued writes in the order of `flush`, given the tables which each
+    // table references.
+    pub(crate) fn take_pending(&mut self, references: &HashMap<String, Vec<String>>) -> Vec<BoxFuture<'a, Result<(), ORMError>>> {
+        let pending = std::mem::take(&mut self.pending);
+        let writes: Vec<(Order, &str)> = pending.iter().map(|p| (p.order, p.table.as_str())).collect();
+        let order = flush_order(&writes, references);
+        let mut pending: Vec<Option<Pending<'a>>> = pending.into_iter().map(Some).collect();
+        order.into_iter().filter_map(|i| pending[i].take()).map(|p| (p.run)()).collect()
+    }
+}
+
+// The number of tables between `table` and the tables which reference
+// nothing, following the longest path. Cycles are cut where they close.
+fn depth(table: &str, references: &HashMap<String, Vec<String>>, path: &mut Vec<String>) -> usize {
+    if path.iter().any(|t| t == table) {
+        return 0;
+    }
+    path.push(table.to_string());
+    let depth = references.get(table).into_iter().flatten()
+        .filter(|referenced| referenced.as_str() != table)
+        .map(|referenced| depth(referenced, references, path) + 1)
+        .max()
+        .unwrap_or(0);
+    path.pop();
+    depth
+}
+
+// The indexes of `writes` in the order of `flush`, see above.
+fn flush_order(writes: &[(Order, &str)], references: &HashMap<String, Vec<String>>) -> Vec<usize> {
+    let depths: Vec<i64> = writes.iter().map(|(_, table)| depth(table, references, &mut Vec::new()) as i64).collect();
+    let mut order: Vec<usize> = (0..writes.len()).collect();
+    order.sort_by_key(|&i| match writes[i].0 {
+        Order::Insert => (Order::Insert, depths[i], i as i64),
+        Order::Update => (Order::Update, 0, i as i64),
+        Order::Delete => (Order::Delete, -depths[i], -(i as i64)),
+    });
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flush_order() {
+        let references = HashMap::from([
+            ("line".to_string(), vec!["order".to_string(), "product".to_string()]),
+            ("order".to_string(), vec!["customer".to_string()]),
+            ("customer".to_string(), vec!["customer".to_string()]),
+            ("a".to_string(), vec!["b".to_string()]),
+            ("b".to_string(), vec!["a".to_string()]),
+        ]);
+        let writes = [
+            (Order::Insert, "line"), (Order::Delete, "customer"), (Order::Insert, "order"), (Order::Update, "line"),
+            (Order::Insert, "customer"), (Order::Delete, "line"), (Order::Delete, "order"), (Order::Insert, "customer"),
+        ];
+        assert_eq!(vec![4, 7, 2, 0, 3, 5, 6, 1], flush_order(&writes, &references));
+        // a cycle is still ordered
+        assert_eq!(vec![0, 1], flush_order(&[(Order::Insert, "a"), (Order::Insert, "b")], &references));
+    }
+}
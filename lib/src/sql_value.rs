@@ -0,0 +1,198 @@
+// Conversions of user types, such as `Email(String)` or `Cents(i64)`, from and
+// to the values stored in columns.
+//
+// A field selects its conversion with `#[column(with = "module")]`, where the
+// module has the functions
+//
+//     fn to_sql_value(value: &Field) -> SqlValue
+//     fn from_sql_value(value: SqlValue) -> Result<Field, ORMError>
+//
+// This module is such a module for any type implementing `ToSqlValue` and
+// `FromSqlValue`: `#[column(with = "ormlib::sql_value")]`.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::ORMError;
+
+// A value as it is stored in a column.
+//
+// Rows keep their values as text, so values read back from the database arrive
+// as `Text` whatever their column type, blobs as their bytes in hex.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+pub trait ToSqlValue {
+    fn to_sql_value(&self) -> SqlValue;
+}
+
+pub trait FromSqlValue: Sized {
+    fn from_sql_value(value: SqlValue) -> Result<Self, ORMError>;
+}
+
+pub fn to_sql_value<T: ToSqlValue>(value: &T) -> SqlValue {
+    value.to_sql_value()
+}
+
+pub fn from_sql_value<T: FromSqlValue>(value: SqlValue) -> Result<T, ORMError> {
+    T::from_sql_value(value)
+}
+
+fn invalid(value: &SqlValue) -> ORMError {
+    ORMError::InvalidValue(format!("{:?}", value))
+}
+
+impl<T: ToSqlValue> ToSqlValue for Option<T> {
+    fn to_sql_value(&self) -> SqlValue {
+        match self {
+            Some(value) => value.to_sql_value(),
+            None => SqlValue::Null,
+        }
+    }
+}
+
+impl<T: FromSqlValue> FromSqlValue for Option<T> {
+    fn from_sql_value(value: SqlValue) -> Result<Self, ORMError> {
+        match value {
+            SqlValue::Null => Ok(None),
+            value => T::from_sql_value(value).map(Some),
+        }
+    }
+}
+
+impl ToSqlValue for String {
+    fn to_sql_value(&self) -> SqlValue {
+        SqlValue::Text(self.clone())
+    }
+}
+
+impl FromSqlValue for String {
+    fn from_sql_value(value: SqlValue) -> Result<Self, ORMError> {
+        match value {
+            SqlValue::Text(text) => Ok(text),
+            SqlValue::Integer(int) => Ok(int.to_string()),
+            SqlValue::Real(real) => Ok(real.to_string()),
+            value => Err(invalid(&value)),
+        }
+    }
+}
+
+impl ToSqlValue for bool {
+    fn to_sql_value(&self) -> SqlValue {
+        SqlValue::Integer(*self as i64)
+    }
+}
+
+impl FromSqlValue for bool {
+    fn from_sql_value(value: SqlValue) -> Result<Self, ORMError> {
+        match i64::from_sql_value(value)? {
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+}
+
+macro_rules! integer {
+    ($($ty:ty),*) => {
+        $(
+            impl ToSqlValue for $ty {
+                fn to_sql_value(&self) -> SqlValue {
+                    // values past i64::MAX are kept as their digits
+                    match i64::try_from(*self) {
+                        Ok(int) => SqlValue::Integer(int),
+                        Err(_) => SqlValue::Text(self.to_string()),
+                    }
+                }
+            }
+
+            impl FromSqlValue for $ty {
+                fn from_sql_value(value: SqlValue) -> Result<Self, ORMError> {
+                    let int = match &value {
+                        SqlValue::Integer(int) => <$ty>::try_from(*int).ok(),
+                        SqlValue::Text(text) => text.parse().ok(),
+                        _ => None,
+                    };
+                    int.ok_or_else(|| invalid(&value))
+                }
+            }
+        )*
+    };
+}
+
+integer!(i8, i16, i32, i64, u8, u16, u32, u64, usize);
+
+macro_rules! real {
+    ($($ty:ty),*) => {
+        $(
+            impl ToSqlValue for $ty {
+                fn to_sql_value(&self) -> SqlValue {
+                    SqlValue::Real(*self as f64)
+                }
+            }
+
+            impl FromSqlValue for $ty {
+                fn from_sql_value(value: SqlValue) -> Result<Self, ORMError> {
+                    let real = match &value {
+                        SqlValue::Real(real) => Some(*real as $ty),
+                        SqlValue::Integer(int) => Some(*int as $ty),
+                        SqlValue::Text(text) => text.parse().ok(),
+                        _ => None,
+                    };
+                    real.ok_or_else(|| invalid(&value))
+                }
+            }
+        )*
+    };
+}
+
+real!(f32, f64);
+
+// The conversions of a `#[column(with = "module")]` field, between the serde
+// form of the field and its column value. Built by the derive.
+#[derive(Debug, Clone, Copy)]
+pub struct Codec {
+    pub to_sql: fn(serde_json::Value) -> Result<SqlValue, ORMError>,
+    pub from_sql: fn(SqlValue) -> Result<serde_json::Value, ORMError>,
+}
+
+impl PartialEq for Codec {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.to_sql as *const (), other.to_sql as *const ())
+            && std::ptr::eq(self.from_sql as *const (), other.from_sql as *const ())
+    }
+}
+
+#[doc(hidden)]
+pub fn encode<T: DeserializeOwned>(value: serde_json::Value, to_sql: fn(&T) -> SqlValue) -> Result<SqlValue, ORMError> {
+    let value: T = serde_json::from_value(value).map_err(|e| ORMError::InvalidValue(e.to_string()))?;
+    Ok(to_sql(&value))
+}
+
+#[doc(hidden)]
+pub fn decode<T: Serialize>(value: SqlValue, from_sql: fn(SqlValue) -> Result<T, ORMError>) -> Result<serde_json::Value, ORMError> {
+    serde_json::to_value(from_sql(value)?).map_err(|e| ORMError::InvalidValue(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sql_value() {
+        assert_eq!(SqlValue::Integer(1), true.to_sql_value());
+        assert_eq!(SqlValue::Null, None::<i32>.to_sql_value());
+        assert_eq!(Some(-5), from_sql_value::<Option<i32>>(SqlValue::Text("-5".to_string())).unwrap());
+        assert_eq!(None, from_sql_value::<Option<i32>>(SqlValue::Null).unwrap());
+        assert!(from_sql_value::<u8>(SqlValue::Integer(300)).is_err());
+        assert_eq!(SqlValue::Text(u64::MAX.to_string()), u64::MAX.to_sql_value());
+        assert_eq!(u64::MAX, from_sql_value::<u64>(u64::MAX.to_sql_value()).unwrap());
+        assert_eq!(2.5, from_sql_value::<f64>(SqlValue::Text("2.5".to_string())).unwrap());
+        assert_eq!("7", from_sql_value::<String>(SqlValue::Integer(7)).unwrap());
+    }
+}
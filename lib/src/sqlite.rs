@@ -1,15 +1,93 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 use async_trait::async_trait;
+use futures::future::BoxFuture;
 use futures::lock::Mutex;
-use rusqlite::Connection;
+use rusqlite::{Connection, params_from_iter};
 use serde::{Deserialize, Serialize};
-use crate::{deserializer_key_values, ORMError, ORMTrait, QueryBuilder, Row, serializer_error, serializer_key_values, serializer_types, serializer_values, TableDeserialize, TableSerialize};
+use crate::cache::{CacheConfig, CacheStats, QueryCache};
+use crate::instrument::QueryTimer;
+use crate::introspect::{self, ColumnInfo, ForeignKeyInfo, IndexInfo};
+use crate::migration::{self, AppliedMigration, Migration};
+use crate::metrics::MetricsSink;
+use crate::retry::{retry, RetryPolicy};
+use crate::schema::{self, Dialect, Mismatch, SqliteTable};
+use crate::session::Order;
+use crate::unit;
+use crate::{aggregate, Aggregate, Clock, Cursor, deserializer_key_values, GroupBy, Instrumentation, Page, Paginate, Session, Tracked, Operation, ORMError, ORMTrait, QueryBuilder, Row, Select, SqlValue, serializer_error, serializer_key_values, serializer_types, serializer_values, TableDeserialize, TableSerialize, SystemClock, to_hex};
 
 #[derive(Debug)]
 pub struct ORM {
     conn: Mutex<Option<Connection>>,
     change_count: Mutex<u32>,
+    clock: std::sync::Mutex<Arc<dyn Clock>>,
+    cache: std::sync::Mutex<Option<QueryCache>>,
+    instrumentation: std::sync::Mutex<Instrumentation>,
+    retry: std::sync::Mutex<RetryPolicy>,
+    metrics: std::sync::Mutex<Option<Arc<dyn MetricsSink>>>,
+    default_timeout: std::sync::Mutex<Option<Duration>>,
+    // held by the open transaction or flush, see `unit`
+    unit: Mutex<()>,
+}
+
+// Interrupts the statement of a connection once the timeout is over: SQLite
+// calls the progress handler of the connection as the statement runs, which
+// stops it past the deadline. The handler is removed with the watchdog.
+struct Watchdog<'c> {
+    conn: &'c Connection,
+    fired: Arc<AtomicBool>,
+}
+
+// The number of virtual machine instructions between two checks of the deadline.
+const PROGRESS_STEPS: i32 = 1000;
+
+impl<'c> Watchdog<'c> {
+    fn start(conn: &'c Connection, timeout: Option<Duration>) -> Self {
+        let fired = Arc::new(AtomicBool::new(false));
+        if let Some(timeout) = timeout {
+            let deadline = Instant::now() + timeout;
+            let interrupted = fired.clone();
+            conn.progress_handler(PROGRESS_STEPS, Some(move || {
+                let over = Instant::now() >= deadline;
+                if over {
+                    interrupted.store(true, Ordering::SeqCst);
+                }
+                over
+            }));
+        }
+        Watchdog { conn, fired }
+    }
+
+    fn check<R>(&self, result: rusqlite::Result<R>) -> Result<R, ORMError> {
+        match result {
+            Err(_) if self.fired.load(Ordering::SeqCst) => Err(ORMError::Timeout),
+            result => Ok(result?),
+        }
+    }
+}
+
+impl Drop for Watchdog<'_> {
+    fn drop(&mut self) {
+        self.conn.progress_handler(0, None::<fn() -> bool>);
+    }
+}
+
+impl rusqlite::ToSql for SqlValue {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        use rusqlite::types::{ToSqlOutput, ValueRef};
+        Ok(ToSqlOutput::Borrowed(match self {
+            SqlValue::Null => ValueRef::Null,
+            SqlValue::Integer(int) => ValueRef::Integer(*int),
+            SqlValue::Real(real) => ValueRef::Real(*real),
+            SqlValue::Text(text) => ValueRef::Text(text.as_bytes()),
+            SqlValue::Blob(bytes) => ValueRef::Blob(bytes),
+        }))
+    }
 }
 
 impl ORM {
@@ -21,8 +99,460 @@ impl ORM {
         Ok(Arc::new(ORM {
             conn: Mutex::new(Some(conn)),
             change_count: 0.into(),
+            clock: std::sync::Mutex::new(Arc::new(SystemClock)),
+            cache: std::sync::Mutex::new(None),
+            instrumentation: std::sync::Mutex::new(Instrumentation::default()),
+            retry: std::sync::Mutex::new(RetryPolicy::none()),
+            metrics: std::sync::Mutex::new(None),
+            default_timeout: std::sync::Mutex::new(None),
+            unit: Mutex::new(()),
         }))
     }
+
+    // Replaces the clock of the timestamp columns, e.g. by a `FixedClock` in tests.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.lock().unwrap() = clock;
+    }
+
+    fn now(&self) -> SystemTime {
+        self.clock.lock().unwrap().now()
+    }
+
+    // Caches the rows of the `find_*` queries, see `cache`.
+    pub fn enable_cache(&self, config: CacheConfig) {
+        *self.cache.lock().unwrap() = Some(QueryCache::new(config));
+    }
+
+    pub fn disable_cache(&self) {
+        *self.cache.lock().unwrap() = None;
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().as_ref().map(QueryCache::stats).unwrap_or_default()
+    }
+
+    // The slow query threshold and redaction of the query logs, see `instrument`.
+    pub fn set_instrumentation(&self, instrumentation: Instrumentation) {
+        *self.instrumentation.lock().unwrap() = instrumentation;
+    }
+
+    // The timeout of the queries without one of their own, none by default.
+    pub fn set_default_timeout(&self, timeout: Option<Duration>) {
+        *self.default_timeout.lock().unwrap() = timeout;
+    }
+
+    fn timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        timeout.or(*self.default_timeout.lock().unwrap())
+    }
+
+    // Retries on transient errors, see `retry`.
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        *self.retry.lock().unwrap() = policy;
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        *self.retry.lock().unwrap()
+    }
+
+    // Statements within a transaction aren't retried on their own, the whole
+    // transaction is.
+    fn statement_retry_policy(&self) -> RetryPolicy {
+        match unit::depth(self) {
+            0 => self.retry_policy(),
+            _ => RetryPolicy::none(),
+        }
+    }
+
+    // Runs `f` in a transaction, which is committed when `f` succeeds and
+    // rolled back when it fails. On a transient error the transaction is rolled
+    // back and runs again as the retry policy allows, so `f` can be called more
+    // than once; the connection is held until the last attempt is over. Within
+    // another transaction it is a savepoint, retried with that transaction.
+    //
+    //     conn.transaction(|orm| Box::pin(async move {
+    //         orm.add(User { id: 0, name: "John".to_string() }).apply().await?;
+    //         Ok(())
+    //     })).await?;
+    pub async fn transaction<R, F>(&self, f: F) -> Result<R, ORMError>
+        where F: for<'c> Fn(&'c ORM) -> BoxFuture<'c, Result<R, ORMError>>
+    {
+        let depth = unit::depth(self);
+        let (_unit, policy) = match depth {
+            0 => (Some(self.unit.lock().await), self.retry_policy()),
+            _ => (None, RetryPolicy::none()),
+        };
+        retry(policy, ORMError::is_transient, || self.attempt(depth, f(self))).await
+    }
+
+    // Runs `work` in a transaction which holds the connection until it is
+    // over, or in a savepoint within another transaction, see `unit`.
+    async fn atomically<R>(&self, work: impl Future<Output = Result<R, ORMError>>) -> Result<R, ORMError> {
+        let depth = unit::depth(self);
+        let _unit = match depth {
+            0 => Some(self.unit.lock().await),
+            _ => None,
+        };
+        self.attempt(depth, work).await
+    }
+
+    // Runs `work` in the transaction or savepoint at `depth`, whose unit the
+    // caller holds. It is rolled back when `work` or the commit fails.
+    async fn attempt<R>(&self, depth: usize, work: impl Future<Output = Result<R, ORMError>>) -> Result<R, ORMError> {
+        let (begin, commit, rollback) = unit::statements(depth, "begin");
+        unit::enter(self, async {
+            self.query_update(begin.as_str()).exec().await?;
+            let r = match work.await {
+                Ok(r) => self.query_update(commit.as_str()).exec().await.map(|_| r),
+                Err(e) => Err(e),
+            };
+            if r.is_err() {
+                for statement in rollback {
+                    // nothing to roll back when the transaction is already over
+                    if let Err(rollback) = self.query_update(statement.as_str()).exec().await {
+                        log::debug!("{:?}", rollback);
+                    }
+                }
+            }
+            r
+        }).await
+    }
+
+    // Told of every statement and of the waits for the connection, see `metrics`.
+    pub fn set_metrics_sink(&self, sink: Option<Arc<dyn MetricsSink>>) {
+        *self.metrics.lock().unwrap() = sink;
+    }
+
+    fn timer(&self, query: &str) -> QueryTimer {
+        let metrics = self.metrics.lock().unwrap().clone();
+        self.instrumentation.lock().unwrap().start(query, "sqlite", metrics)
+    }
+
+    async fn connection(&self) -> unit::Held<'_, Option<Connection>> {
+        let start = Instant::now();
+        // waits for the transaction of another task to be over
+        let unit = match unit::depth(self) {
+            0 => Some(self.unit.lock().await),
+            _ => None,
+        };
+        let conn = self.conn.lock().await;
+        let metrics = self.metrics.lock().unwrap().clone();
+        if let Some(metrics) = metrics {
+            metrics.connection_wait("sqlite", start.elapsed());
+        }
+        unit::Held::new(unit, conn)
+    }
+
+    // Drops the cached rows of the tables which `statement` writes. Writes
+    // call it before and after they run, so that the rows of a query which
+    // runs in between aren't kept.
+    fn invalidate(&self, statement: &str) {
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            cache.invalidate(statement);
+        }
+    }
+
+    // The rows of a `find_*` query, from the cache when it has them.
+    async fn find_rows(&self, query: &str, params: &[SqlValue], timeout: Option<Duration>) -> Result<Vec<Row>, ORMError> {
+        let cached = self.cache.lock().unwrap().as_mut().map(|cache| (cache.get(query, params), cache.generation()));
+        let generation = match cached {
+            Some((Some(rows), _)) => return Ok(rows),
+            Some((None, generation)) => generation,
+            None => 0,
+        };
+        let rows = QueryBuilder { timeout, params: params.to_vec(), ..self.query(query) }.exec().await?;
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            cache.put(query, params, generation, rows.clone());
+        }
+        Ok(rows)
+    }
+
+    // The number of prepared statements kept for reuse, 16 by default; least
+    // recently used statements are dropped first.
+    pub async fn set_statement_cache_capacity(&self, capacity: usize) -> Result<(), ORMError> {
+        let conn = self.conn.lock().await;
+        if conn.is_none() {
+            return Err(ORMError::NoConnection);
+        }
+        conn.as_ref().unwrap().set_prepared_statement_cache_capacity(capacity);
+        Ok(())
+    }
+
+    // A unit of work over this connection, see `session`.
+    pub fn session(&self) -> Session<'_, ORM> {
+        Session::new(self)
+    }
+
+    // A text literal for the catalog queries.
+    fn literal(text: &str) -> String {
+        format!("'{}'", text.replace('\'', "''"))
+    }
+
+    // The tables of the database, by name, see `introspect`.
+    pub async fn tables(&self) -> Result<Vec<String>, ORMError> {
+        let query = "select name from sqlite_master where type = 'table' and name not like 'sqlite\\_%' escape '\\' order by name";
+        let rows = self.query::<Row>(query).exec().await?;
+        Ok(introspect::names(rows))
+    }
+
+    // The columns of a table in their order, none when there is no such table.
+    pub async fn columns(&self, table: &str) -> Result<Vec<ColumnInfo>, ORMError> {
+        self.columns_in(None, table).await
+    }
+
+    pub async fn indexes(&self, table: &str) -> Result<Vec<IndexInfo>, ORMError> {
+        self.indexes_in(None, table).await
+    }
+
+    pub async fn foreign_keys(&self, table: &str) -> Result<Vec<ForeignKeyInfo>, ORMError> {
+        self.foreign_keys_in(None, table).await
+    }
+
+    // The arguments of a pragma function, in the attached database `schema`
+    // when there is one.
+    fn pragma_args(arg: String, schema: Option<&str>) -> String {
+        match schema {
+            Some(schema) => format!("{}, {}", arg, ORM::literal(schema)),
+            None => arg,
+        }
+    }
+
+    async fn columns_in(&self, schema: Option<&str>, table: &str) -> Result<Vec<ColumnInfo>, ORMError> {
+        let query = format!("select name, type, \"notnull\", dflt_value, pk from pragma_table_info({})",
+            ORM::pragma_args(ORM::literal(table), schema));
+        let rows = self.query::<Row>(query.as_str()).exec().await?;
+        let keys = rows.iter().filter(|row| row.get::<i64>(4).unwrap_or(0) > 0).count();
+        Ok(rows.iter().map(|row| {
+            let declared_type: String = row.get(1).unwrap_or_default();
+            let primary_key = row.get::<i64>(4).unwrap_or(0) > 0;
+            // the one column of an INTEGER PRIMARY KEY is the rowid
+            let auto_increment = primary_key && keys == 1 && declared_type.eq_ignore_ascii_case("integer");
+            ColumnInfo {
+                name: row.get(0).unwrap_or_default(),
+                declared_type,
+                nullable: row.get::<i64>(2) != Some(1) && !auto_increment,
+                default: row.get(3),
+                primary_key,
+                auto_increment,
+            }
+        }).collect())
+    }
+
+    async fn indexes_in(&self, schema: Option<&str>, table: &str) -> Result<Vec<IndexInfo>, ORMError> {
+        let query = format!("select il.name, il.\"unique\", ii.name from pragma_index_list({}) as il, pragma_index_info({}) as ii \
+            order by il.name, ii.seqno", ORM::pragma_args(ORM::literal(table), schema), ORM::pragma_args("il.name".to_string(), schema));
+        let rows = self.query::<Row>(query.as_str()).exec().await?;
+        Ok(introspect::indexes(rows))
+    }
+
+    async fn foreign_keys_in(&self, schema: Option<&str>, table: &str) -> Result<Vec<ForeignKeyInfo>, ORMError> {
+        let query = format!("select id, \"from\", \"table\", \"to\", on_update, on_delete from pragma_foreign_key_list({}) \
+            order by id, seq", ORM::pragma_args(ORM::literal(table), schema));
+        let rows = self.query::<Row>(query.as_str()).exec().await?;
+        Ok(introspect::foreign_keys(rows))
+    }
+
+    // The applied migrations in the order of their versions, see `migration`.
+    pub async fn applied_migrations(&self) -> Result<Vec<AppliedMigration>, ORMError> {
+        self.query_update(format!("CREATE TABLE IF NOT EXISTS {} (version TEXT PRIMARY KEY, name TEXT NOT NULL, checksum TEXT NOT NULL, applied_at TEXT NOT NULL, dirty INTEGER NOT NULL DEFAULT 0)", migration::TABLE).as_str()).exec().await?;
+        let query = format!("select version, name, checksum, applied_at, dirty from {} order by version", migration::TABLE);
+        let rows = self.query::<Row>(query.as_str()).exec().await?;
+        Ok(migration::applied(rows))
+    }
+
+    // Runs the up script of the migration and records it, in one transaction.
+    pub async fn apply_migration(&self, migration: &Migration) -> Result<(), ORMError> {
+        self.applied_migrations().await?;
+        let record = format!("insert into {} (version, name, checksum, applied_at) values ({}, {}, {}, {})", migration::TABLE,
+            ORM::literal(migration.version.as_str()), ORM::literal(migration.name.as_str()), ORM::literal(migration.checksum().as_str()),
+            ORM::literal(crate::clock::timestamp(self.now(), false).as_str()));
+        self.run_migration(migration::statements(migration.up.as_str()), record).await
+    }
+
+    // Runs the down script of the migration and forgets it, in one transaction.
+    pub async fn revert_migration(&self, migration: &Migration) -> Result<(), ORMError> {
+        let statements = migration::statements(migration.down.as_str());
+        if statements.is_empty() {
+            return Err(ORMError::InvalidValue(format!("migration {}_{} has no down script", migration.version, migration.name)));
+        }
+        let record = format!("delete from {} where version = {}", migration::TABLE, ORM::literal(migration.version.as_str()));
+        self.run_migration(statements, record).await
+    }
+
+    async fn run_migration(&self, statements: Vec<String>, record: String) -> Result<(), ORMError> {
+        self.transaction(|conn| {
+            let (statements, record) = (statements.clone(), record.clone());
+            Box::pin(async move {
+                for statement in statements {
+                    conn.query_update(statement.as_str()).exec().await?;
+                }
+                conn.query_update(record.as_str()).exec().await?;
+                Ok(())
+            })
+        }).await
+    }
+
+    // Takes over the count of the `ormlib_last_change` table of older releases
+    // as the applied changes, see `migration`.
+    async fn adopt_last_change(&self) -> Result<Vec<AppliedMigration>, ORMError> {
+        let rows = self.query::<Row>("select last from ormlib_last_change").exec().await?;
+        let last: u32 = rows.first().and_then(|row| row.get(0)).unwrap_or_default();
+        let now = ORM::literal(crate::clock::timestamp(self.now(), false).as_str());
+        let statements: Vec<String> = (1..=last).map(|count| {
+            let migration = migration::change(count, "");
+            format!("insert into {} (version, name, checksum, applied_at) values ({}, {}, '', {})", migration::TABLE,
+                ORM::literal(migration.version.as_str()), ORM::literal(migration.name.as_str()), now)
+        }).collect();
+        self.run_migration(statements, "DROP TABLE ormlib_last_change".to_string()).await?;
+        self.applied_migrations().await
+    }
+
+    // Fails with `ORMError::SchemaMismatch` when the table of `T` doesn't
+    // match its fields, see `schema`.
+    pub async fn validate<T: TableDeserialize>(&self) -> Result<(), ORMError> {
+        let columns = self.columns_in(T::same_schema().as_deref(), T::same_name().as_str()).await?;
+        let mismatches = schema::mismatches::<T>(Dialect::Sqlite, &columns);
+        match mismatches.is_empty() {
+            true => Ok(()),
+            false => Err(ORMError::SchemaMismatch(T::same_name(), mismatches)),
+        }
+    }
+
+    // The statements which make the table of `T` match its fields, none when it
+    // does. The table is rebuilt, so run them in a transaction with the foreign
+    // keys off.
+    pub async fn diff<T: TableDeserialize>(&self) -> Result<Vec<String>, ORMError> {
+        let (schema, name) = (T::same_schema(), T::same_name());
+        let columns = self.columns_in(schema.as_deref(), name.as_str()).await?;
+        let mismatches = schema::mismatches::<T>(Dialect::Sqlite, &columns);
+        if mismatches.is_empty() {
+            return Ok(Vec::new());
+        }
+        if mismatches.contains(&Mismatch::MissingTable) {
+            return Ok(vec![schema::create_table::<T>(Dialect::Sqlite, ORM::quote, ORM::quote_table(schema, name.as_str()).as_str())]);
+        }
+        let master = ORM::quote_table(schema.clone(), "sqlite_master");
+        let query = format!("select sql from {} where type = 'table' and name = {}", master, ORM::literal(name.as_str()));
+        let sql: Option<String> = self.query::<Row>(query.as_str()).exec().await?.first().and_then(|row| row.get(0));
+        let query = format!("select name, sql from {} where type = 'index' and tbl_name = {} and sql is not null",
+            master, ORM::literal(name.as_str()));
+        let index_sql = self.query::<Row>(query.as_str()).exec().await?.iter()
+            .map(|row| (row.get(0).unwrap_or_default(), row.get(1).unwrap_or_default()))
+            .collect();
+        let table = SqliteTable {
+            autoincrement: sql.is_some_and(|sql| sql.to_lowercase().contains("autoincrement")),
+            indexes: self.indexes_in(schema.as_deref(), name.as_str()).await?,
+            index_sql,
+            foreign_keys: self.foreign_keys_in(schema.as_deref(), name.as_str()).await?,
+        };
+        Ok(schema::sqlite_rebuild::<T>(ORM::quote, schema.as_deref(), name.as_str(), &columns, table))
+    }
+
+    // Updates only the changed fields of `tracked`, or nothing when none has
+    // changed, and takes it as saved. Returns the number of updated rows.
+    pub async fn save<T>(&self, tracked: &mut Tracked<T>) -> Result<usize, ORMError>
+        where T: for<'a> Deserialize<'a> + TableDeserialize + TableSerialize + Serialize + 'static
+    {
+        if !tracked.is_dirty() {
+            return Ok(0);
+        }
+        if let Some(hooks) = tracked.hooks() {
+            hooks.before_update().await?;
+        }
+        tracked.validate()?;
+        let (query, params, versioned) = self.update_query(&**tracked, Some(tracked.changed_fields()));
+        let r = QueryBuilder { params, ..self.query_update(query.as_str()) }.exec().await?;
+        if versioned && r == 0 {
+            return Err(ORMError::StaleEntity);
+        }
+        tracked.saved()?;
+        if let Some(hooks) = tracked.hooks() {
+            hooks.after_update().await?;
+        }
+        Ok(r)
+    }
+
+    // Walks the whole table of `T` in batches in `id` order, by keyset
+    // pagination, until `f` fails or the rows run out.
+    pub async fn find_each<T, F>(&self, batch_size: usize, mut f: F) -> Result<(), ORMError>
+        where T: for<'a> Deserialize<'a> + TableDeserialize + Serialize + Debug + 'static,
+              F: FnMut(Vec<T>) -> Result<(), ORMError>
+    {
+        let mut after: Option<Cursor> = None;
+        loop {
+            let page: Page<T> = self.find_all().paginate_by_key("id", after.as_ref(), batch_size).run().await?;
+            if !page.items.is_empty() {
+                f(page.items)?;
+            }
+            match page.next {
+                Some(next) => after = Some(next),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // The statements of the entities bind their values to placeholders, so
+    // that they are prepared once whatever the values.
+    fn insert_query<T>(&self, data: &T) -> (String, Vec<SqlValue>)
+        where T: TableDeserialize + TableSerialize + Serialize
+    {
+        let table_name = ORM::quote_table(data.schema(), data.name().as_str());
+        let types = serializer_types::to_string(data, ORM::quote, T::columns()).unwrap();
+        let (values, params) = serializer_values::to_placeholders(data, T::columns(), self.now()).unwrap();
+        (format!("insert into {table_name} {types} values {values}"), params)
+    }
+
+    // The update of `data`, or of only some of its fields, and whether it
+    // checks the version column.
+    fn update_query<T>(&self, data: &T, only: Option<Vec<String>>) -> (String, Vec<SqlValue>, bool)
+        where T: TableDeserialize + TableSerialize + Serialize
+    {
+        let table_name = ORM::quote_table(data.schema(), data.name().as_str());
+        let (key_value_str, mut params) = serializer_key_values::to_placeholders(data, ORM::quote, T::columns(), self.now(), only).unwrap();
+        // remove first and last char
+        let key_value = &key_value_str[1..key_value_str.len()-1];
+        params.push(serializer_values::field_to_value(data, T::columns(), "id").unwrap());
+        let id_column = ORM::quote("id");
+        let mut query: String = format!("update {table_name} set {key_value} where {id_column} = ?");
+        let version = T::columns().into_iter().find(|column| column.version);
+        if let Some(version) = &version {
+            params.push(serializer_values::field_to_value(data, T::columns(), version.name.as_str()).unwrap());
+            query = format!("{query} and {} = ?", ORM::quote(version.name.as_str()));
+        }
+        (query, params, version.is_some())
+    }
+
+    // Marks the row of `data` as removed when `T` soft deletes, unless forced.
+    fn delete_query<T>(&self, data: &T, force: bool) -> (String, Vec<SqlValue>)
+        where T: TableDeserialize + TableSerialize + Serialize
+    {
+        let table_name = ORM::quote_table(data.schema(), data.name().as_str());
+        let id = serializer_values::field_to_value(data, T::columns(), "id").unwrap();
+        let id_column = ORM::quote("id");
+        let column = match T::soft_delete() {
+            Some(column) if !force => column,
+            _ => return (format!("delete from {table_name} where {id_column} = ?"), vec![id]),
+        };
+        let now = match T::columns().into_iter().find(|c| c.name == column) {
+            Some(column) => serializer_values::timestamp_value(&column, self.now()),
+            None => SqlValue::Text(crate::clock::timestamp(self.now(), false)),
+        };
+        let column = ORM::quote(column.as_str());
+        (format!("update {table_name} set {column} = ? where {id_column} = ? and {column} is null"), vec![now, id])
+    }
+
+    // Clears the soft delete column of the row of `data`.
+    fn restore_query<T>(&self, data: &T) -> Result<(String, Vec<SqlValue>), ORMError>
+        where T: TableDeserialize + TableSerialize + Serialize
+    {
+        let column = T::soft_delete().ok_or_else(|| {
+            ORMError::InvalidValue(format!("{} can't be restored without #[table(soft_delete = \"column\")]", T::same_name()))
+        })?;
+        let table_name = ORM::quote_table(data.schema(), data.name().as_str());
+        let id = serializer_values::field_to_value(data, T::columns(), "id").unwrap();
+        let id_column = ORM::quote("id");
+        let column = ORM::quote(column.as_str());
+        Ok((format!("update {table_name} set {column} = null where {id_column} = ?"), vec![id]))
+    }
 }
 #[async_trait]
 impl ORMTrait<ORM> for ORM {
@@ -30,15 +560,16 @@ impl ORMTrait<ORM> for ORM {
     fn add<T>(&self, data: T) -> QueryBuilder<T, T, ORM>
         where T: for<'a> Deserialize<'a> + TableDeserialize + TableSerialize + Serialize + Debug + 'static
     {
-        let table_name = data.name();
-        let types = serializer_types::to_string(&data).unwrap();
-        let values = serializer_values::to_string(&data).unwrap();
-        let query: String = format!("insert into {table_name} {types} values {values}");
+        // The insert is built by `apply`, after the hooks and the validation.
         let qb = QueryBuilder::<T,T, ORM> {
-            query: query,
+            query: String::new(),
             entity: Default::default(),
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(Some(Operation::Insert(data))),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
         };
         qb
     }
@@ -71,15 +602,20 @@ impl ORMTrait<ORM> for ORM {
     fn find_one<T: TableDeserialize>(&self, id: u64) -> QueryBuilder<Option<T>, T, ORM>
         where T: TableDeserialize + TableSerialize + for<'a> Deserialize<'a> + 'static
     {
-        let table_name = T::same_name();
+        let table_name = ORM::quote_table(T::same_schema(), T::same_name().as_str());
+        let id_column = ORM::quote("id");
 
-        let query: String = format!("select * from {table_name} where id = {id}");
+        let filter = format!("{id_column} = ?");
 
         let qb = QueryBuilder::<Option<T>, T, ORM> {
-            query,
+            query: String::new(),
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: None,
+            select: Some(Select::new(table_name, Some(filter), ORM::not_deleted::<T>())),
+            params: vec![SqlValue::Integer(id as i64)],
         };
         qb
     }
@@ -89,73 +625,142 @@ impl ORMTrait<ORM> for ORM {
 
     {
 
-        let table_name = T::same_name();
-
-        let query: String = format!("select * from {table_name} where {query_where}");
+        let table_name = ORM::quote_table(T::same_schema(), T::same_name().as_str());
 
         let qb = QueryBuilder::<Vec<T>, T, ORM> {
-            query,
+            query: String::new(),
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: None,
+            select: Some(Select::new(table_name, Some(query_where.to_string()), ORM::not_deleted::<T>())),
+            params: Vec::new(),
         };
         qb
     }
 
     fn find_all<T>(&self) -> QueryBuilder<Vec<T>, T, ORM>
         where T: for<'a> Deserialize<'a> + TableDeserialize + Debug + 'static {
-        let table_name = T::same_name();
-
-        let query: String = format!("select * from {table_name}");
+        let table_name = ORM::quote_table(T::same_schema(), T::same_name().as_str());
 
         let qb = QueryBuilder::<Vec<T>, T, ORM> {
-            query,
+            query: String::new(),
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: None,
+            select: Some(Select::new(table_name, None, ORM::not_deleted::<T>())),
+            params: Vec::new(),
         };
         qb
     }
 
-    fn modify<T>(&self, data: T) -> QueryBuilder<usize, (), ORM>
+    fn modify<T>(&self, data: T) -> QueryBuilder<usize, T, ORM>
         where T: TableDeserialize + TableSerialize + Serialize + 'static
     {
-        let table_name = data.name();
-        let key_value_str = serializer_key_values::to_string(&data).unwrap();
-        // remove first and last char
-        let key_value = &key_value_str[1..key_value_str.len()-1];
-        let id = data.get_id();
-        let query: String = format!("update {table_name} set {key_value} where id = {id}");
-        let qb = QueryBuilder::<usize, (), ORM> {
-            query,
+        // The update is built by `run`, after the hooks and the validation.
+        let qb = QueryBuilder::<usize, T, ORM> {
+            query: String::new(),
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(Some(Operation::Update(data))),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
         };
         qb
     }
 
-    fn remove<T>(&self, data: T) -> QueryBuilder<usize, (), ORM>
+    fn remove<T>(&self, data: T) -> QueryBuilder<usize, T, ORM>
         where T: TableDeserialize + TableSerialize + Serialize + 'static
     {
-        let table_name = data.name();
-        let id = data.get_id();
-        let query: String = format!("delete from {table_name} where id = {id}");
-        let qb = QueryBuilder::<usize, (), ORM> {
-            query,
+        // The delete is built by `run`, after the hooks.
+        let qb = QueryBuilder::<usize, T, ORM> {
+            query: String::new(),
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(Some(Operation::Delete(data, false))),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
         };
         qb
     }
 
+    fn force_remove<T>(&self, data: T) -> QueryBuilder<'_, usize, T, ORM>
+        where T: TableDeserialize + TableSerialize + Serialize + 'static
+    {
+        // The delete is built by `run`, after the hooks.
+        QueryBuilder::<usize, T, ORM> {
+            query: String::new(),
+            entity: std::marker::PhantomData,
+            orm: self,
+            result: std::marker::PhantomData,
+            data: Mutex::new(Some(Operation::Delete(data, true))),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
+        }
+    }
+
+    fn restore<T>(&self, data: T) -> QueryBuilder<'_, usize, T, ORM>
+        where T: TableDeserialize + TableSerialize + Serialize + 'static
+    {
+        // The update is built by `run`, which fails when `T` doesn't soft delete.
+        QueryBuilder::<usize, T, ORM> {
+            query: String::new(),
+            entity: std::marker::PhantomData,
+            orm: self,
+            result: std::marker::PhantomData,
+            data: Mutex::new(Some(Operation::Restore(data))),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
+        }
+    }
+
+    fn count<T: TableDeserialize>(&self) -> Aggregate<'_, i64, T, ORM> {
+        Aggregate::new(self, "count", None)
+    }
+
+    fn exists<T: TableDeserialize>(&self) -> Aggregate<'_, bool, T, ORM> {
+        Aggregate::new(self, "exists", None)
+    }
+
+    fn sum<T: TableDeserialize, V: FromStr>(&self, column: &str) -> Aggregate<'_, Option<V>, T, ORM> {
+        Aggregate::new(self, "sum", Some(column))
+    }
+
+    fn avg<T: TableDeserialize, V: FromStr>(&self, column: &str) -> Aggregate<'_, Option<V>, T, ORM> {
+        Aggregate::new(self, "avg", Some(column))
+    }
+
+    fn min<T: TableDeserialize, V: FromStr>(&self, column: &str) -> Aggregate<'_, Option<V>, T, ORM> {
+        Aggregate::new(self, "min", Some(column))
+    }
+
+    fn max<T: TableDeserialize, V: FromStr>(&self, column: &str) -> Aggregate<'_, Option<V>, T, ORM> {
+        Aggregate::new(self, "max", Some(column))
+    }
+
+    fn group_by<T: TableDeserialize, K: FromStr>(&self, column: &str) -> GroupBy<'_, K, T, ORM> {
+        GroupBy::new(self, column)
+    }
+
     fn query<T>(&self, query: &str) -> QueryBuilder<Vec<T>, T, ORM> {
         let qb = QueryBuilder::<Vec<T>, T, ORM> {
             query: query.to_string(),
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
         };
         qb
     }
@@ -166,6 +771,10 @@ impl ORMTrait<ORM> for ORM {
             entity: std::marker::PhantomData,
             orm: self,
             result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: None,
+            select: None,
+            params: Vec::new(),
         };
         qb
     }
@@ -195,6 +804,14 @@ impl ORMTrait<ORM> for ORM {
         escaped
     }
 
+    fn quote(identifier: &str) -> String {
+        format!("\"{}\"", identifier.replace("\"", "\"\""))
+    }
+
+    fn json_path(column: &str, path: &str) -> String {
+        format!("json_extract({}, '{}')", ORM::quote(column), path.replace('\'', "''"))
+    }
+
     fn escape_json(input: &str) -> String {
         let input = input.to_string();
         let mut escaped = input.clone();
@@ -226,77 +843,157 @@ impl ORMTrait<ORM> for ORM {
     }
 
     async fn change(&self, update_query: &str) -> anyhow::Result<(), ORMError> {
-        let _ = self.query_update("CREATE TABLE ormlib_last_change (id INTEGER PRIMARY KEY AUTOINCREMENT, last INTEGER)").exec().await;
-        let rows = self.query("select id, last from ormlib_last_change").exec().await?;
-        let last = if rows.len() == 0 {
-            let _ = self.query_update("insert into ormlib_last_change (last) values (0)").exec().await;
-            0
-        } else {
-            let row: &Row = rows.get(0).unwrap();
-            let last: u32 = row.get(1).unwrap();
-            last
-        };
+        let mut applied = self.applied_migrations().await?;
+        if !applied.iter().any(AppliedMigration::from_code) && self.tables().await?.iter().any(|table| table == "ormlib_last_change") {
+            applied = self.adopt_last_change().await?;
+        }
         let mut change_count = self.change_count.lock().await;
-        //self.change_count = self.change_count + 1;
-        *change_count = *change_count + 1;
-        if *change_count > last {
-            let _updated_rows: usize = self.query_update(update_query).exec().await?;
-            let _updated_rows: usize = self.query_update(format!("update ormlib_last_change set last = {}",*change_count).as_str()).exec().await?;
+        *change_count += 1;
+        let migration = migration::change(*change_count, update_query);
+        if applied.iter().any(|applied| applied.version == migration.version) {
+            return Ok(());
         }
-        Ok(())
+        self.apply_migration(&migration).await
     }
 }
 
 impl<T> QueryBuilder<'_, usize, T, ORM>{
     pub async fn exec(&self) -> Result<usize, ORMError> {
-        log::debug!("{:?}", self.query);
-        let conn = self.orm.conn.lock().await;
-        if conn.is_none() {
-            return Err(ORMError::NoConnection);
-        }
-        let conn = conn.as_ref().unwrap();
-        let r = conn.execute(self.query.as_str(),(),)?;
-        Ok(r)
+        let timer = self.orm.timer(self.query.as_str());
+        self.orm.invalidate(self.query.as_str());
+        let r = async {
+            let conn = self.orm.connection().await;
+            if conn.is_none() {
+                return Err(ORMError::NoConnection);
+            }
+            let conn = conn.as_ref().unwrap();
+            let watchdog = Watchdog::start(conn, self.orm.timeout(self.timeout));
+            let r = watchdog.check(conn.execute(self.query.as_str(), params_from_iter(&self.params)))?;
+            Ok(r)
+        }.await;
+        timer.finish(r.as_ref().copied());
+        self.orm.invalidate(self.query.as_str());
+        r
     }
 }
 
 impl<T> QueryBuilder<'_, T,T, ORM>{
     pub async fn apply(&self) -> Result<T, ORMError>
-        where T: for<'a> Deserialize<'a> + TableDeserialize + TableSerialize + Debug + 'static
+        where T: for<'a> Deserialize<'a> + TableDeserialize + TableSerialize + Serialize + Debug + 'static
     {
-        log::debug!("{:?}", self.query);
-        let r = {
-            let conn = self.orm.conn.lock().await;
+        let (mut query, mut params) = (self.query.clone(), self.params.clone());
+        let mut data = self.data.lock().await;
+        if let Some(Operation::Insert(data)) = &mut *data {
+            if let Some(hooks) = data.hooks() {
+                hooks.before_insert().await?;
+            }
+            data.validate()?;
+            (query, params) = self.orm.insert_query(data);
+        }
+        let timer = self.orm.timer(query.as_str());
+        self.orm.invalidate(query.as_str());
+        let result = async {
+            let conn = self.orm.connection().await;
             if conn.is_none() {
                 return Err(ORMError::NoConnection);
             }
             let conn = conn.as_ref().unwrap();
-            let _r = conn.execute(self.query.as_str(),(),)?;
-            let r = conn.last_insert_rowid();
-            r
-        };
-        let rows: Vec<T> = self.orm.find_many(format!("rowid = {}", r).as_str()).run().await?;
+            let watchdog = Watchdog::start(conn, self.orm.timeout(self.timeout));
+            let r = watchdog.check(conn.prepare_cached(query.as_str()).and_then(|mut stmt| stmt.execute(params_from_iter(&params))))?;
+            Ok((r, conn.last_insert_rowid()))
+        }.await;
+        timer.finish(result.as_ref().map(|(r, _)| *r));
+        self.orm.invalidate(query.as_str());
+        let (_r, r) = result?;
+        let rows: Vec<T> = QueryBuilder { timeout: self.timeout, params: vec![SqlValue::Integer(r)], ..self.orm.find_many("rowid = ?") }.run().await?;
         if rows.len() == 0 {
             return Err(ORMError::InsertError);
         }
         let t_opt = rows.into_iter().next();
         match t_opt {
-            Some(t) => Ok(t),
+            Some(mut t) => {
+                if let Some(hooks) = t.hooks() {
+                    hooks.after_insert().await?;
+                }
+                Ok(t)
+            }
             None => Err(ORMError::InsertError),
         }
 
     }
 }
 
-impl<T> QueryBuilder<'_, usize,T, ORM> {
+impl<T> QueryBuilder<'_, usize, T, ORM> {
+    // The statement of `run`, retried when it fails with a transient error.
+    async fn execute(&self, query: &str, params: &[SqlValue]) -> Result<usize, ORMError> {
+        let timer = self.orm.timer(query);
+        self.orm.invalidate(query);
+        let (orm, statement, timeout) = (self.orm, query, self.orm.timeout(self.timeout));
+        let result = retry(orm.statement_retry_policy(), ORMError::is_transient, move || async move {
+            let conn = orm.connection().await;
+            if conn.is_none() {
+                return Err(ORMError::NoConnection);
+            }
+            let conn = conn.as_ref().unwrap();
+            let watchdog = Watchdog::start(conn, timeout);
+            let r = watchdog.check(conn.prepare_cached(statement).and_then(|mut stmt| stmt.execute(params_from_iter(params))))?;
+            Ok(r)
+        }).await;
+        timer.finish(result.as_ref().copied());
+        self.orm.invalidate(query);
+        result
+    }
+}
+
+impl QueryBuilder<'_, usize, (), ORM> {
     pub async fn run(&self) -> Result<usize, ORMError> {
-        log::debug!("{:?}", self.query);
-        let conn = self.orm.conn.lock().await;
-        if conn.is_none() {
-            return Err(ORMError::NoConnection);
+        self.execute(self.query.as_str(), &self.params).await
+    }
+}
+
+impl<T> QueryBuilder<'_, usize, T, ORM>
+    where T: TableDeserialize + TableSerialize + Serialize + 'static
+{
+    // Runs the statement of the entity between its hooks.
+    pub async fn run(&self) -> Result<usize, ORMError> {
+        let (mut query, mut params) = (self.query.clone(), self.params.clone());
+        // an update which checks the version column, so no affected row is a conflict
+        let mut versioned = false;
+        let mut data = self.data.lock().await;
+        match &mut *data {
+            Some(Operation::Update(data)) => {
+                if let Some(hooks) = data.hooks() {
+                    hooks.before_update().await?;
+                }
+                data.validate()?;
+                (query, params, versioned) = self.orm.update_query(data, None);
+            }
+            Some(Operation::Delete(data, force)) => {
+                if let Some(hooks) = data.hooks() {
+                    hooks.before_delete().await?;
+                }
+                (query, params) = self.orm.delete_query(data, *force);
+            }
+            Some(Operation::Restore(data)) => (query, params) = self.orm.restore_query(data)?,
+            _ => {}
+        }
+        let r = self.execute(query.as_str(), &params).await?;
+        if versioned && r == 0 {
+            return Err(ORMError::StaleEntity);
+        }
+        match &mut *data {
+            Some(Operation::Update(data)) => {
+                if let Some(hooks) = data.hooks() {
+                    hooks.after_update().await?;
+                }
+            }
+            Some(Operation::Delete(data, _)) => {
+                if let Some(hooks) = data.hooks() {
+                    hooks.after_delete().await?;
+                }
+            }
+            _ => {}
         }
-        let conn = conn.as_ref().unwrap();
-        let r = conn.execute(self.query.as_str(),(),)?;
         Ok(r)
     }
 }
@@ -305,9 +1002,42 @@ impl<T> QueryBuilder<'_, usize,T, ORM> {
 impl<T> QueryBuilder<'_, Option<T>,T, ORM>
     where T: for<'a> Deserialize<'a> + TableDeserialize + Debug + 'static
 {
+    pub fn with_deleted(&self) -> QueryBuilder<'_, Option<T>, T, ORM> {
+        QueryBuilder::<Option<T>,T, ORM> {
+            query: self.query.clone(),
+            entity: std::marker::PhantomData,
+            orm: self.orm,
+            result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: self.timeout,
+            select: self.select.clone().map(|select| Select { deleted: None, ..select }),
+            params: self.params.clone(),
+        }
+    }
+
+    pub fn only_deleted(&self) -> QueryBuilder<'_, Option<T>, T, ORM> {
+        QueryBuilder::<Option<T>,T, ORM> {
+            query: self.query.clone(),
+            entity: std::marker::PhantomData,
+            orm: self.orm,
+            result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: self.timeout,
+            select: self.select.clone().map(|select| Select { deleted: ORM::deleted::<T>(), ..select }),
+            params: self.params.clone(),
+        }
+    }
+
+    // Runs the query, with a snapshot of the entity for `save`.
+    pub async fn run_tracked(&self) -> Result<Option<Tracked<T>>, ORMError>
+        where T: Serialize
+    {
+        Ok(self.run().await?.map(Tracked::new))
+    }
+
     pub async fn run(&self) -> Result<Option<T>, ORMError> {
 
-        let rows  = self.orm.query(self.query.clone().as_str()).exec().await?;
+        let rows = self.orm.find_rows(self.sql().as_str(), &self.params, self.timeout).await?;
         let columns: Vec<String> =T::fields();
         if rows.len() == 0 {
             return Ok(None);
@@ -331,7 +1061,10 @@ impl<T> QueryBuilder<'_, Option<T>,T, ORM>
             }
             let user_str = format!("{{{}}}", column_str.join(","));
             // log::debug!("zzz{}", user_str);
-            let user: T = deserializer_key_values::from_str(&user_str).unwrap();
+            let mut user: T = deserializer_key_values::from_str(&user_str, T::columns()).unwrap();
+            if let Some(hooks) = user.hooks() {
+                hooks.after_load().await?;
+            }
             Ok(Some(user))
 
         }
@@ -342,25 +1075,35 @@ impl<T> QueryBuilder<'_, Option<T>,T, ORM>
 impl<R> QueryBuilder<'_, Vec<Row>,R, ORM> {
     pub async fn exec(&self) -> Result<Vec<Row>, ORMError>
     {
-        log::debug!("{:?}", self.query);
-        let conn = self.orm.conn.lock().await;
+        let timer = self.orm.timer(self.query.as_str());
+        let rows = retry(self.orm.statement_retry_policy(), ORMError::is_transient, move || self.rows()).await;
+        timer.finish(rows.as_ref().map(Vec::len));
+        rows
+    }
+
+    async fn rows(&self) -> Result<Vec<Row>, ORMError>
+    {
+        let conn = self.orm.connection().await;
         if conn.is_none() {
             return Err(ORMError::NoConnection);
         }
         let conn = conn.as_ref().unwrap();
-        let stmt_result = conn.prepare( self.query.as_str());
+        let watchdog = Watchdog::start(conn, self.orm.timeout(self.timeout));
+        // Kept in the statement cache of the connection, so that repeated
+        // queries are parsed once.
+        let stmt_result = conn.prepare_cached( self.query.as_str());
         if stmt_result.is_err() {
             let e = stmt_result.err().unwrap();
             log::error!("{:?}", e);
-            return Err(ORMError::RusqliteError(e));
+            return watchdog.check(Err(e));
         }
         let mut stmt = stmt_result.unwrap();
         let mut result: Vec<Row> = Vec::new();
-        let person_iter = stmt.query_map([], |row| {
+        let person_iter = stmt.query_map(params_from_iter(&self.params), |row| {
             let mut i = 0;
             let mut r: Row = Row::new();
             loop {
-                let res: rusqlite::Result<i32>= row.get(i);
+                let res: rusqlite::Result<i64>= row.get(i);
 
                 match  res{
                     Ok(v) => {
@@ -384,13 +1127,25 @@ impl<R> QueryBuilder<'_, Vec<Row>,R, ORM> {
                     }
                 }
 
+                // Blobs are read as hex text, see `Row`.
+                let res: rusqlite::Result<Vec<u8>>= row.get(i);
+                if let Ok(v) = res {
+                    r.set(i.try_into().unwrap(), Some(to_hex(&v)));
+                }
+
+                // Reals, e.g. of `avg`, are read as their text.
+                if let Ok(rusqlite::types::ValueRef::Real(v)) = row.get_ref(i) {
+                    r.set(i.try_into().unwrap(), Some(v));
+                }
+
                 i = i + 1;
             }
 
             result.push(r);
             Ok(())
-        })?;
-        for _x in person_iter {
+        });
+        for x in watchdog.check(person_iter)? {
+            watchdog.check(x)?;
         }
         // log::debug!("{:?}", result);
 
@@ -400,13 +1155,134 @@ impl<R> QueryBuilder<'_, Vec<Row>,R, ORM> {
 
 }
 
+impl<T: TableDeserialize> Aggregate<'_, i64, T, ORM> {
+    pub async fn run(&self) -> Result<i64, ORMError> {
+        let rows = self.orm.query::<Row>(self.sql()?.as_str()).exec().await?;
+        aggregate::count(rows)
+    }
+}
+
+impl<T: TableDeserialize> Aggregate<'_, bool, T, ORM> {
+    pub async fn run(&self) -> Result<bool, ORMError> {
+        let rows = self.orm.query::<Row>(self.sql()?.as_str()).exec().await?;
+        Ok(!rows.is_empty())
+    }
+}
+
+impl<T: TableDeserialize, V: FromStr> Aggregate<'_, Option<V>, T, ORM> {
+    pub async fn run(&self) -> Result<Option<V>, ORMError> {
+        let rows = self.orm.query::<Row>(self.sql()?.as_str()).exec().await?;
+        aggregate::value(rows)
+    }
+}
+
+impl<T: TableDeserialize, K: FromStr> Aggregate<'_, Vec<(K, i64)>, T, ORM> {
+    pub async fn run(&self) -> Result<Vec<(K, i64)>, ORMError> {
+        let rows = self.orm.query::<Row>(self.sql()?.as_str()).exec().await?;
+        aggregate::groups(rows)
+    }
+}
+
+impl<'a> Session<'a, ORM> {
+    // Loads the entity through the identity map.
+    pub async fn find_one<T>(&mut self, id: u64) -> Result<Option<T>, ORMError>
+        where T: for<'b> Deserialize<'b> + TableDeserialize + TableSerialize + Debug + Clone + Send + 'static
+    {
+        if let Some(entity) = self.cached::<T>(id.to_string()) {
+            return Ok(Some(entity));
+        }
+        let entity: Option<T> = self.orm.find_one(id).run().await?;
+        if let Some(entity) = &entity {
+            self.cache(id.to_string(), entity.clone());
+        }
+        Ok(entity)
+    }
+
+    pub fn add<T>(&mut self, entity: T)
+        where T: for<'b> Deserialize<'b> + TableDeserialize + TableSerialize + Serialize + Debug + Send + Sync + 'static
+    {
+        let orm = self.orm;
+        self.enqueue(Order::Insert, T::same_name(), move || Box::pin(async move {
+            orm.add(entity).apply().await.map(|_| ())
+        }));
+    }
+
+    // Also replaces the entity in the identity map.
+    pub fn modify<T>(&mut self, entity: T) -> Result<(), ORMError>
+        where T: TableDeserialize + TableSerialize + Serialize + Clone + Send + Sync + 'static
+    {
+        self.cache(Self::id_of(&entity)?, entity.clone());
+        let orm = self.orm;
+        self.enqueue(Order::Update, T::same_name(), move || Box::pin(async move {
+            orm.modify(entity).run().await.map(|_| ())
+        }));
+        Ok(())
+    }
+
+    pub fn remove<T>(&mut self, entity: T) -> Result<(), ORMError>
+        where T: TableDeserialize + TableSerialize + Serialize + Send + Sync + 'static
+    {
+        self.evict::<T>(Self::id_of(&entity)?);
+        let orm = self.orm;
+        self.enqueue(Order::Delete, T::same_name(), move || Box::pin(async move {
+            orm.remove(entity).run().await.map(|_| ())
+        }));
+        Ok(())
+    }
+
+    // Runs the queued writes in one transaction, or in a savepoint within
+    // `transaction`, ordered by the foreign keys of their tables, see
+    // `session`. On an error it is rolled back, and the identity map is
+    // cleared as it may hold unsaved changes.
+    pub async fn flush(&mut self) -> Result<(), ORMError> {
+        let mut references = HashMap::new();
+        for table in self.pending_tables() {
+            let foreign_keys = self.orm.foreign_keys(table.as_str()).await?;
+            references.insert(table, foreign_keys.into_iter().map(|fk| fk.referenced_table).collect::<Vec<_>>());
+        }
+        let pending = self.take_pending(&references);
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let r = self.orm.atomically(async {
+            for run in pending {
+                run.await?;
+            }
+            Ok(())
+        }).await;
+        if r.is_err() {
+            self.clear();
+        }
+        r
+    }
+}
+
+impl<T> Paginate<'_, T, ORM> {
+    pub async fn run(&self) -> Result<Page<T>, ORMError>
+        where T: for<'a> Deserialize<'a> + TableDeserialize + Serialize + Debug + 'static
+    {
+        let query = QueryBuilder::<Vec<T>, T, ORM> {
+            query: self.query().to_string(),
+            entity: std::marker::PhantomData,
+            orm: self.orm,
+            result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: None,
+            select: None,
+            params: self.params().to_vec(),
+        };
+        let items: Vec<T> = query.run().await?;
+        self.page(items)
+    }
+}
+
 impl<T> QueryBuilder<'_, Vec<T>,T, ORM> {
     pub async fn run(&self) -> Result<Vec<T>, ORMError>
         where T: for<'a> Deserialize<'a> + TableDeserialize + Debug + 'static
     {
 
         let mut result: Vec<T> = Vec::new();
-        let rows  = self.orm.query(self.query.clone().as_str()).exec().await?;
+        let rows = self.orm.find_rows(self.sql().as_str(), &self.params, self.timeout).await?;
         let columns: Vec<String> =T::fields();
         for row in rows {
             let mut column_str: Vec<String> = Vec::new();
@@ -426,14 +1302,16 @@ impl<T> QueryBuilder<'_, Vec<T>,T, ORM> {
             }
             let user_str = format!("{{{}}}", column_str.join(","));
             // log::info!("{}", user_str);
-            let user_result: std::result::Result<T, serializer_error::Error> = deserializer_key_values::from_str(&user_str);
+            let user_result: std::result::Result<T, serializer_error::Error> = deserializer_key_values::from_str(&user_str, T::columns());
             match user_result {
-                Ok(user) => {
+                Ok(mut user) => {
+                    if let Some(hooks) = user.hooks() {
+                        hooks.after_load().await?;
+                    }
                     result.push(user);
                 }
                 Err(e) => {
                     log::error!("{:?}", e);
-                    log::error!("{}", user_str);
                     return Err(ORMError::Unknown);
                 }
             }
@@ -443,6 +1321,50 @@ impl<T> QueryBuilder<'_, Vec<T>,T, ORM> {
         Ok(result)
     }
 
+    // Runs the query, with snapshots of the entities for `save`.
+    pub async fn run_tracked(&self) -> Result<Vec<Tracked<T>>, ORMError>
+        where T: for<'a> Deserialize<'a> + TableDeserialize + Serialize + Debug + 'static
+    {
+        Ok(self.run().await?.into_iter().map(Tracked::new).collect())
+    }
+
+    // Includes soft-deleted rows.
+    pub fn with_deleted(&self) -> QueryBuilder<'_, Vec<T>, T, ORM>
+        where T: TableDeserialize
+    {
+        QueryBuilder::<Vec<T>,T, ORM> {
+            query: self.query.clone(),
+            entity: std::marker::PhantomData,
+            orm: self.orm,
+            result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: self.timeout,
+            select: self.select.clone().map(|select| Select { deleted: None, ..select }),
+            params: self.params.clone(),
+        }
+    }
+
+    // Only takes soft-deleted rows.
+    pub fn only_deleted(&self) -> QueryBuilder<'_, Vec<T>, T, ORM>
+        where T: TableDeserialize
+    {
+        QueryBuilder::<Vec<T>,T, ORM> {
+            query: self.query.clone(),
+            entity: std::marker::PhantomData,
+            orm: self.orm,
+            result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: self.timeout,
+            select: self.select.clone().map(|select| Select { deleted: ORM::deleted::<T>(), ..select }),
+            params: self.params.clone(),
+        }
+    }
+
+    // The first `page_size` rows after the cursor in `key` order, see `pagination`.
+    pub fn paginate_by_key(&self, key: &str, after: Option<&Cursor>, page_size: usize) -> Paginate<'_, T, ORM> {
+        Paginate::new(self.orm, self.sql().as_str(), &self.params, key, after, page_size)
+    }
+
     pub fn limit(&self, limit: i32) -> QueryBuilder<Vec<T>, T, ORM> {
 
         let qb =  QueryBuilder::<Vec<T>,T, ORM> {
@@ -450,8 +1372,11 @@ impl<T> QueryBuilder<'_, Vec<T>,T, ORM> {
             entity: std::marker::PhantomData,
             orm: self.orm,
             result: std::marker::PhantomData,
+            data: Mutex::new(None),
+            timeout: self.timeout,
+            select: self.select.clone().map(|select| Select { limit: Some(limit), ..select }),
+            params: self.params.clone(),
         };
         qb
     }
 }
-
@@ -0,0 +1,119 @@
+// Change tracking: a `Tracked<T>` keeps the serde form of the entity as it was
+// loaded, so that `save` can update only the columns of the fields which have
+// changed since, and skip the update when none has.
+//
+//     let mut user: Tracked<User> = conn.find_one(1).run_tracked().await?.unwrap();
+//     user.age = 31;
+//     conn.save(&mut user).await?;   // update "user" set "age" = 31 where "id" = 1
+
+use std::ops::{Deref, DerefMut};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{ORMError, TableDeserialize};
+
+#[derive(Debug, Clone)]
+pub struct Tracked<T> {
+    entity: T,
+    snapshot: serde_json::Value,
+}
+
+impl<T: Serialize> Tracked<T> {
+    pub fn new(entity: T) -> Self {
+        let snapshot = serde_json::to_value(&entity).unwrap_or_default();
+        Tracked { entity, snapshot }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.entity
+    }
+
+    // The fields which differ from the snapshot, in column order. The key and
+    // the columns which the ORM maintains itself never count as changed.
+    pub fn changed_fields(&self) -> Vec<String>
+        where T: TableDeserialize
+    {
+        let current = serde_json::to_value(&self.entity).unwrap_or_default();
+        T::columns().into_iter()
+            .filter(|column| column.name != "id" && !column.version && !column.created_at && !column.updated_at)
+            .filter(|column| current.get(column.name.as_str()) != self.snapshot.get(column.name.as_str()))
+            .map(|column| column.name)
+            .collect()
+    }
+
+    pub fn is_dirty(&self) -> bool
+        where T: TableDeserialize
+    {
+        !self.changed_fields().is_empty()
+    }
+
+    // Takes the entity as saved: the current fields become the snapshot, and
+    // the version is the one which the update has written.
+    pub(crate) fn saved(&mut self) -> Result<(), ORMError>
+        where T: TableDeserialize + DeserializeOwned
+    {
+        let mut current = serde_json::to_value(&self.entity).map_err(|e| ORMError::InvalidValue(e.to_string()))?;
+        if let Some(version) = T::columns().into_iter().find(|column| column.version) {
+            if let Some(field) = current.get_mut(version.name.as_str()) {
+                let next = field.as_i64().map(|v| v + 1).ok_or_else(|| ORMError::InvalidValue(field.to_string()))?;
+                *field = serde_json::Value::from(next);
+                self.entity = serde_json::from_value(current.clone()).map_err(|e| ORMError::InvalidValue(e.to_string()))?;
+            }
+        }
+        self.snapshot = current;
+        Ok(())
+    }
+}
+
+impl<T> Deref for Tracked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.entity
+    }
+}
+
+impl<T> DerefMut for Tracked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.entity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Column;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct User {
+        id: i32,
+        name: String,
+        age: i32,
+        version: i32,
+    }
+
+    impl TableDeserialize for User {
+        fn columns() -> Vec<Column> {
+            let mut version = Column::new("version");
+            version.version = true;
+            vec![Column::new("id"), Column::new("name"), Column::new("age"), version]
+        }
+    }
+
+    #[test]
+    fn test_tracked() {
+        let mut user = Tracked::new(User { id: 1, name: "John".to_string(), age: 30, version: 3 });
+        assert!(!user.is_dirty());
+        user.age = 31;
+        user.name = "John".to_string();
+        user.version = 7;
+        assert_eq!(vec!["age"], user.changed_fields());
+        user.version = 3;
+        user.saved().unwrap();
+        assert!(!user.is_dirty());
+        assert_eq!(4, user.version);
+        assert_eq!(31, user.into_inner().age);
+    }
+}
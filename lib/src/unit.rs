@@ -0,0 +1,98 @@
+// The units of work of a task, i.e. its transactions and session flushes: a
+// unit holds the connection of its ORM until it is over, so that the
+// statements of other tasks don't run in the middle of it, while the
+// statements of the task itself go through. A unit within a unit of the same
+// ORM is a savepoint.
+//
+// Tasks spawned within a unit aren't part of it: their statements wait for the
+// unit to be over, so the unit mustn't wait for them.
+
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+
+use futures::lock::MutexGuard;
+
+tokio::task_local! {
+    // the ORMs of the open units, by address, once per level
+    static UNITS: Vec<usize>;
+}
+
+fn address<O>(orm: &O) -> usize {
+    orm as *const O as usize
+}
+
+// The number of open units of `orm` in the task, 0 outside of them.
+pub(crate) fn depth<O>(orm: &O) -> usize {
+    UNITS.try_with(|units| units.iter().filter(|unit| **unit == address(orm)).count()).unwrap_or(0)
+}
+
+// Runs `work` as a unit of `orm`, one level deeper.
+pub(crate) async fn enter<O, F: Future>(orm: &O, work: F) -> F::Output {
+    let mut units = UNITS.try_with(Clone::clone).unwrap_or_default();
+    units.push(address(orm));
+    UNITS.scope(units, work).await
+}
+
+// The connection of a statement outside of the units of its task, with the
+// unit lock it waited for, which is held until the statement is over so that
+// no other task begins a unit in the middle of it.
+pub(crate) struct Held<'a, C> {
+    conn: MutexGuard<'a, C>,
+    _unit: Option<MutexGuard<'a, ()>>,
+}
+
+impl<'a, C> Held<'a, C> {
+    pub(crate) fn new(unit: Option<MutexGuard<'a, ()>>, conn: MutexGuard<'a, C>) -> Self {
+        Held { conn, _unit: unit }
+    }
+}
+
+impl<C> Deref for Held<'_, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.conn
+    }
+}
+
+impl<C> DerefMut for Held<'_, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.conn
+    }
+}
+
+// The statements which begin, commit and roll back a unit at `depth`.
+pub(crate) fn statements(depth: usize, begin: &str) -> (String, String, Vec<String>) {
+    match depth {
+        0 => (begin.to_string(), "commit".to_string(), vec!["rollback".to_string()]),
+        depth => {
+            let savepoint = format!("ormlib_{}", depth);
+            (format!("savepoint {}", savepoint), format!("release savepoint {}", savepoint),
+                vec![format!("rollback to savepoint {}", savepoint), format!("release savepoint {}", savepoint)])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static A: u8 = 1;
+    static B: u8 = 2;
+
+    #[tokio::test]
+    async fn test_depth() {
+        assert_eq!(0, depth(&A));
+        enter(&A, async {
+            assert_eq!(1, depth(&A));
+            enter(&B, async {
+                assert_eq!((1, 1), (depth(&A), depth(&B)));
+                enter(&A, async { assert_eq!(2, depth(&A)) }).await;
+            }).await;
+            // other tasks aren't in the unit
+            assert_eq!(0, tokio::spawn(async { depth(&A) }).await.unwrap());
+        }).await;
+        assert_eq!(0, depth(&A));
+        assert_eq!("release savepoint ormlib_2", statements(2, "begin").1);
+    }
+}
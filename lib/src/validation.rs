@@ -0,0 +1,169 @@
+// The checks of the `#[validate(...)]` attributes of the derive, which are run
+// on the entity of an `add` or `modify` before its SQL is built:
+//
+//     #[validate(length(min = 1, max = 64))]   text, in characters, or a Vec
+//     #[validate(range(min = 0, max = 100))]   numbers
+//     #[validate(regex = "^[a-z]+$")]          text
+//
+// `None` values of `Option` fields are not checked. All the violations of an
+// entity are returned together in `ORMError::Validation`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+
+use regex::Regex;
+
+use crate::ORMError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+pub trait Length {
+    fn length(&self) -> Option<usize>;
+}
+
+impl Length for String {
+    fn length(&self) -> Option<usize> {
+        Some(self.chars().count())
+    }
+}
+
+impl<T> Length for Vec<T> {
+    fn length(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl<T: Length> Length for Option<T> {
+    fn length(&self) -> Option<usize> {
+        self.as_ref().and_then(Length::length)
+    }
+}
+
+pub trait Number {
+    fn number(&self) -> Option<f64>;
+}
+
+macro_rules! number {
+    ($($ty:ty),*) => {
+        $(
+            impl Number for $ty {
+                fn number(&self) -> Option<f64> {
+                    Some(*self as f64)
+                }
+            }
+        )*
+    };
+}
+
+number!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f32, f64);
+
+impl<T: Number> Number for Option<T> {
+    fn number(&self) -> Option<f64> {
+        self.as_ref().and_then(Number::number)
+    }
+}
+
+pub trait Text {
+    fn text(&self) -> Option<&str>;
+}
+
+impl Text for String {
+    fn text(&self) -> Option<&str> {
+        Some(self.as_str())
+    }
+}
+
+impl<T: Text> Text for Option<T> {
+    fn text(&self) -> Option<&str> {
+        self.as_ref().and_then(Text::text)
+    }
+}
+
+fn violation(errors: &mut Vec<FieldError>, field: &str, message: String) {
+    errors.push(FieldError { field: field.to_string(), message });
+}
+
+pub fn length<T: Length>(errors: &mut Vec<FieldError>, field: &str, value: &T, min: Option<usize>, max: Option<usize>) {
+    let Some(length) = value.length() else {
+        return;
+    };
+    if let Some(min) = min.filter(|min| length < *min) {
+        violation(errors, field, format!("length must be at least {}", min));
+    }
+    if let Some(max) = max.filter(|max| length > *max) {
+        violation(errors, field, format!("length must be at most {}", max));
+    }
+}
+
+pub fn range<T: Number>(errors: &mut Vec<FieldError>, field: &str, value: &T, min: Option<f64>, max: Option<f64>) {
+    let Some(number) = value.number() else {
+        return;
+    };
+    if let Some(min) = min.filter(|min| number < *min) {
+        violation(errors, field, format!("must be at least {}", min));
+    }
+    if let Some(max) = max.filter(|max| number > *max) {
+        violation(errors, field, format!("must be at most {}", max));
+    }
+}
+
+// Patterns are checked by the derive, and compiled once.
+pub fn regex<T: Text>(errors: &mut Vec<FieldError>, field: &str, value: &T, pattern: &str) {
+    static CACHE: OnceLock<Mutex<HashMap<String, Regex>>> = OnceLock::new();
+    let Some(text) = value.text() else {
+        return;
+    };
+    let is_match = {
+        let mut cache = CACHE.get_or_init(Default::default).lock().unwrap();
+        let regex = cache.entry(pattern.to_string())
+            .or_insert_with(|| Regex::new(pattern).expect("Wrong validate regex"));
+        regex.is_match(text)
+    };
+    if !is_match {
+        violation(errors, field, format!("must match {}", pattern));
+    }
+}
+
+pub fn result(errors: Vec<FieldError>) -> Result<(), ORMError> {
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(ORMError::Validation(errors)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validation() {
+        let mut errors = Vec::new();
+        length(&mut errors, "name", &"äbc".to_string(), Some(1), Some(3));
+        length(&mut errors, "nick", &None::<String>, Some(1), None);
+        range(&mut errors, "age", &Some(30), Some(0.0), Some(150.0));
+        regex(&mut errors, "code", &"ab12".to_string(), "^[a-z]+[0-9]+$");
+        assert!(result(errors).is_ok());
+
+        let mut errors = Vec::new();
+        length(&mut errors, "name", &"".to_string(), Some(1), Some(3));
+        range(&mut errors, "age", &-1i32, Some(0.0), None);
+        regex(&mut errors, "code", &Some("12ab".to_string()), "^[a-z]+[0-9]+$");
+        assert_eq!(errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(), vec![
+            "name: length must be at least 1",
+            "age: must be at least 0",
+            "code: must match ^[a-z]+[0-9]+$",
+        ]);
+        assert!(matches!(result(errors), Err(ORMError::Validation(errors)) if errors.len() == 3));
+    }
+}
@@ -1,4 +1,4 @@
-use darling::FromDeriveInput;
+use darling::{FromDeriveInput, FromField, FromMeta};
 use proc_macro::{self, TokenStream};
 use quote::quote;
 use syn::{parse_macro_input, DeriveInput};
@@ -7,15 +7,56 @@ use syn::{parse_macro_input, DeriveInput};
 #[darling(default, attributes(table), forward_attrs(allow, doc, cfg))]
 struct Opts {
     name: Option<String>,
+    schema: Option<String>,
+    soft_delete: Option<String>,
+    hooks: bool,
 }
 
-#[proc_macro_derive(TableSerialize, attributes(table))]
+#[derive(FromField, Default)]
+#[darling(default, attributes(column))]
+struct FieldOpts {
+    as_integer: bool,
+    json: bool,
+    binary: bool,
+    with: Option<String>,
+    version: bool,
+    created_at: bool,
+    updated_at: bool,
+    // whether the database fills the column in on insert, by default only `id`
+    generated: Option<bool>,
+}
+
+#[derive(FromField, Default)]
+#[darling(default, attributes(validate))]
+struct ValidateOpts {
+    length: Option<Bounds>,
+    range: Option<Bounds>,
+    regex: Option<String>,
+}
+
+#[derive(FromMeta, Default)]
+#[darling(default)]
+struct Bounds {
+    min: Option<syn::Lit>,
+    max: Option<syn::Lit>,
+}
+
+#[proc_macro_derive(TableSerialize, attributes(table, column, validate))]
 pub fn derive(input: TokenStream) -> TokenStream {
     // println!("!!!!!!!!!!!!!");
 
     let input = parse_macro_input!(input);
     let opts = Opts::from_derive_input(&input).expect("Wrong options");
     let DeriveInput { ident, .. } = input;
+    let schema = match opts.schema {
+        Some(x) => quote! {
+            fn schema(&self) -> Option<String> {
+                Some(#x.to_string())
+            }
+        },
+        None => quote! {
+        },
+    };
     let answer = match opts.name {
         Some(x) => quote! {
             fn name(&self) -> String {
@@ -36,6 +77,8 @@ pub fn derive(input: TokenStream) -> TokenStream {
     let output = quote! {
         impl ormlib::TableSerialize for #ident {
             #answer
+
+            #schema
         }
     };
     // println!("++++++++++++++++");
@@ -43,7 +86,7 @@ pub fn derive(input: TokenStream) -> TokenStream {
     output.into()
 }
 
-#[proc_macro_derive(TableDeserialize, attributes(table))]
+#[proc_macro_derive(TableDeserialize, attributes(table, column, validate))]
 pub fn derive_de(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input);
     let opts = Opts::from_derive_input(&input).expect("Wrong options");
@@ -54,8 +97,70 @@ pub fn derive_de(input: TokenStream) -> TokenStream {
     };
 
     let mut fields: Vec<String> = Vec::new();
+    let mut columns: Vec<proc_macro2::TokenStream> = Vec::new();
+    let mut checks: Vec<proc_macro2::TokenStream> = Vec::new();
     for f in data.fields.iter() {
-        fields.push(f.ident.as_ref().unwrap().to_string());
+        let field = f.ident.as_ref().unwrap().to_string();
+        let member = f.ident.as_ref().unwrap();
+        let validate_opts = ValidateOpts::from_field(f).expect("Wrong validate options");
+        if let Some(Bounds { min, max }) = validate_opts.length {
+            let min = bound(min, quote!(usize));
+            let max = bound(max, quote!(usize));
+            checks.push(quote! {
+                ormlib::validation::length(&mut errors, #field, &self.#member, #min, #max);
+            });
+        }
+        if let Some(Bounds { min, max }) = validate_opts.range {
+            let min = bound(min, quote!(f64));
+            let max = bound(max, quote!(f64));
+            checks.push(quote! {
+                ormlib::validation::range(&mut errors, #field, &self.#member, #min, #max);
+            });
+        }
+        if let Some(pattern) = validate_opts.regex {
+            regex::Regex::new(pattern.as_str()).expect("Wrong validate regex");
+            checks.push(quote! {
+                ormlib::validation::regex(&mut errors, #field, &self.#member, #pattern);
+            });
+        }
+        let field_opts = FieldOpts::from_field(f).expect("Wrong column options");
+        let as_integer = field_opts.as_integer;
+        let json = field_opts.json;
+        let binary = field_opts.binary;
+        let version = field_opts.version;
+        let created_at = field_opts.created_at;
+        let updated_at = field_opts.updated_at;
+        let generated = field_opts.generated.unwrap_or(field == "id");
+        let ty = &f.ty;
+        let codec = match field_opts.with {
+            Some(with) => {
+                let module: syn::Path = syn::parse_str(with.as_str()).expect("Wrong column codec module");
+                quote! {
+                    Some(ormlib::sql_value::Codec {
+                        to_sql: |value| ormlib::sql_value::encode::<#ty>(value, #module::to_sql_value),
+                        from_sql: |value| ormlib::sql_value::decode::<#ty>(value, #module::from_sql_value),
+                    })
+                }
+            }
+            None => quote! { None },
+        };
+        let rust_type = quote!(#ty).to_string();
+        columns.push(quote! {
+            {
+                let mut column = ormlib::Column::new(#field);
+                column.as_integer = #as_integer;
+                column.json = #json;
+                column.binary = #binary;
+                column.codec = #codec;
+                column.version = #version;
+                column.created_at = #created_at;
+                column.updated_at = #updated_at;
+                column.rust_type = #rust_type.to_string();
+                column.generated = #generated;
+                column
+            }
+        });
+        fields.push(field);
 
     }
     let code1: String = r#"
@@ -83,6 +188,48 @@ pub fn derive_de(input: TokenStream) -> TokenStream {
 
     let code_token: proc_macro2::TokenStream = code.parse().unwrap(); // Преобразование строки в TokenStream
 
+    let schema = match opts.schema {
+        Some(x) => quote! {
+            fn same_schema() -> Option<String> {
+                Some(#x.to_string())
+            }
+        },
+        None => quote! {
+        },
+    };
+
+    let soft_delete = match opts.soft_delete {
+        Some(x) => quote! {
+            fn soft_delete() -> Option<String> {
+                Some(#x.to_string())
+            }
+        },
+        None => quote! {
+        },
+    };
+
+    let hooks = match opts.hooks {
+        true => quote! {
+            fn hooks(&mut self) -> Option<&mut dyn ormlib::EntityHooks> {
+                Some(self)
+            }
+        },
+        false => quote! {
+        },
+    };
+
+    let validate = match checks.is_empty() {
+        false => quote! {
+            fn validate(&self) -> Result<(), ormlib::ORMError> {
+                let mut errors = Vec::new();
+                #(#checks)*
+                ormlib::validation::result(errors)
+            }
+        },
+        true => quote! {
+        },
+    };
+
     let  answer = match opts.name {
         Some(x) => quote! {
             fn same_name() -> String {
@@ -97,9 +244,28 @@ pub fn derive_de(input: TokenStream) -> TokenStream {
         impl ormlib::TableDeserialize for #ident {
             #answer
 
+            #schema
+
+            #soft_delete
+
+            #hooks
+
+            #validate
+
             #code_token
+
+            fn columns() -> Vec<ormlib::Column> {
+                vec![#(#columns),*]
+            }
         }
     };
 
     output.into()
 }
+
+fn bound(lit: Option<syn::Lit>, ty: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    match lit {
+        Some(lit) => quote! { Some(#lit as #ty) },
+        None => quote! { None },
+    }
+}